@@ -1,3 +1,30 @@
-fn main() {
-    println!("Hello, world!");
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("check") if args.len() > 1 => check_scripts(&args[1..]),
+        _ => {
+            eprintln!("Usage: cli check <script.phybkc>...");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn check_scripts(paths: &[String]) -> ExitCode {
+    let mut ok = true;
+    for path in paths {
+        match dsl::load_script(path) {
+            Ok(_) => println!("{}: OK", path),
+            Err(e) => {
+                eprintln!("{}\n", e);
+                ok = false;
+            }
+        }
+    }
+    if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
 }
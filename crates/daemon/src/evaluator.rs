@@ -1,40 +1,75 @@
 use crate::keyboard::resolve_trigger_key;
 use async_trait::async_trait;
-use dsl::{Condition, ConditionEvaluator};
-use std::collections::BTreeSet;
-use std::sync::{Arc, Mutex};
+use dsl::{Condition, ConditionEvaluator, TriggerCombinations};
+use engine::{HeldKeys, KeyEvents, KeyWait, WaitFor, WaitState, all_held};
+use profile::KeyNames;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct KeyConditionEvaluator {
-    pub held_keys: Arc<Mutex<BTreeSet<u16>>>,
+    pub held_keys: &'static HeldKeys,
+    /// Key names of the profile the executor belongs to
+    pub names: Arc<KeyNames>,
+    pub key_events: KeyEvents,
 }
 
 #[async_trait]
 impl ConditionEvaluator for KeyConditionEvaluator {
     async fn evaluate(&self, condition: &Condition) -> bool {
+        self.select(condition).await.is_some()
+    }
+
+    async fn select(&self, condition: &Condition) -> Option<usize> {
         match condition {
             Condition::NowInput(combos) => {
-                let held = self.held_keys.lock().unwrap();
-                for combo in combos {
-                    let mut all_match = true;
-                    for tk in &combo.0 {
-                        if let Some(sc) = resolve_trigger_key(tk) {
-                            if !held.contains(&sc) {
-                                all_match = false;
-                                break;
-                            }
-                        } else {
-                            all_match = false;
-                            break;
-                        }
-                    }
-                    if all_match {
-                        return true;
-                    }
-                }
-                false
+                let held = self.held_keys.snapshot();
+                // The first alternative whose keys are all held
+                self.resolve(combos)
+                    .iter()
+                    .position(|keys| all_held(keys, &held))
+            }
+            Condition::WaitInput(combos) => self.wait(combos, WaitFor::Pressed, None).await,
+            Condition::WaitInputTime(combos, ms) => {
+                self.wait(combos, WaitFor::Pressed, Some(*ms)).await
             }
-            _ => false,
+            Condition::WaitReleased(combos) => self.wait(combos, WaitFor::Released, None).await,
+            Condition::WaitReleasedTime(combos, ms) => {
+                self.wait(combos, WaitFor::Released, Some(*ms)).await
+            }
+            _ => None,
+        }
+    }
+}
+
+impl KeyConditionEvaluator {
+    /// `Some` with the index of the alternative that matched, see
+    /// [`KeyEvents::wait`].
+    async fn wait(
+        &self,
+        combos: &[TriggerCombinations],
+        wait_for: WaitFor,
+        timeout_ms: Option<u64>,
+    ) -> Option<usize> {
+        let deadline = timeout_ms.map(|ms| Instant::now() + Duration::from_millis(ms));
+        let wait = KeyWait::new(self.resolve(combos), wait_for, deadline);
+        match self.key_events.wait(self.held_keys, wait).await {
+            WaitState::Matched(i) => Some(i),
+            _ => None,
         }
     }
+
+    // `None` for combinations with a key unknown to the profile
+    fn resolve(&self, combos: &[TriggerCombinations]) -> Vec<Option<Vec<u16>>> {
+        combos
+            .iter()
+            .map(|combo| {
+                combo
+                    .0
+                    .iter()
+                    .map(|tk| resolve_trigger_key(&tk.node, &self.names))
+                    .collect()
+            })
+            .collect()
+    }
 }
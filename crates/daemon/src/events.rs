@@ -0,0 +1,11 @@
+use crate::state::KEY_EVENTS;
+
+pub use engine::KeyEvent;
+
+/// Publish to the scripts waiting on key events, see
+/// [`engine::KeyEvents::publish`].
+pub fn publish(event: KeyEvent) {
+    if let Some(events) = KEY_EVENTS.get() {
+        events.publish(event);
+    }
+}
@@ -1,23 +1,32 @@
-use crate::keyboard::send_key_event;
-use crate::state::{CURRENT_PROFILE, EXECUTOR, HELD_KEYS, SCRIPT_TRIGGERS};
-use std::collections::BTreeSet;
+use crate::events::{self, KeyEvent};
+use crate::keyboard::{send_key_event, send_unicode_char};
+use crate::state::{HELD_KEYS, SNAPSHOT, Snapshot};
+use engine::{BlockId, Decision, Engine, Replay};
+use profile::KeyAction;
+use std::cell::{Cell, RefCell};
 use std::ptr;
 use std::sync::Arc;
+use std::time::Instant;
 use windows_sys::Win32::Foundation::*;
 use windows_sys::Win32::UI::WindowsAndMessaging::*;
 
+thread_local! {
+    // The hook always runs on the thread that installed it, so the engine's
+    // mutable state never needs a lock
+    static ENGINE: RefCell<Engine> = RefCell::new(Engine::default());
+    // Fires when keys held back by the engine time out, 0 when none are
+    static POLL_TIMER: Cell<usize> = const { Cell::new(0) };
+}
+
 pub unsafe extern "system" fn low_level_keyboard_proc(
     n_code: i32,
     w_param: WPARAM,
     l_param: LPARAM,
 ) -> LRESULT {
     if n_code >= 0 {
+        let timestamp = Instant::now();
         let kb_struct = unsafe { *(l_param as *const KBDLLHOOKSTRUCT) };
 
-        if (kb_struct.flags & LLKHF_INJECTED) != 0 {
-            return unsafe { CallNextHookEx(ptr::null_mut(), n_code, w_param, l_param) };
-        }
-
         let scan_code = kb_struct.scanCode as u16;
         let is_extended = (kb_struct.flags & LLKHF_EXTENDED) != 0;
         let actual_sc = if is_extended {
@@ -26,71 +35,146 @@ pub unsafe extern "system" fn low_level_keyboard_proc(
             scan_code
         };
 
-        let event = w_param as u32;
-        let is_key_down = event == WM_KEYDOWN || event == WM_SYSKEYDOWN;
+        let message = w_param as u32;
+        let event = KeyEvent {
+            scancode: actual_sc,
+            down: message == WM_KEYDOWN || message == WM_SYSKEYDOWN,
+            timestamp,
+            injected: (kb_struct.flags & LLKHF_INJECTED) != 0,
+        };
 
-        if let Some(held) = HELD_KEYS.get() {
-            let mut h = held.lock().unwrap();
-            if is_key_down {
-                h.insert(actual_sc);
+        // Update the held set before publishing: a waiter that subscribes in
+        // between sees the key as already held rather than missing it
+        if !event.injected {
+            HELD_KEYS.set(event.scancode, event.down);
+        }
+        events::publish(event);
 
-                if let Some(triggers_lock) = SCRIPT_TRIGGERS.get() {
-                    let triggers = triggers_lock.read().unwrap();
-                    let h_ref: &BTreeSet<u16> = &h;
-                    let mut best_match: Option<(&Vec<u16>, &dsl::Block)> = None;
+        let guard = SNAPSHOT.load();
+        let Some(snapshot) = guard.as_deref() else {
+            return unsafe { CallNextHookEx(ptr::null_mut(), n_code, w_param, l_param) };
+        };
+        let (decision, replay, deadline) = ENGINE.with_borrow_mut(|engine| {
+            let decision = engine.handle(&snapshot.bindings, &event);
+            (decision, engine.take_replay(), engine.deadline())
+        });
+        // Keys held back by a trigger that didn't complete, or by a dual-role
+        // key now decided
+        send_replay(snapshot, replay);
+        schedule_poll(deadline);
+        match decision {
+            Decision::Pass => {}
+            Decision::Swallow => return 1,
+            Decision::Remap(target) => {
+                unsafe {
+                    send_key_event(target, event.down, false);
+                }
+                return 1;
+            }
+            Decision::Run(block) => {
+                run_block(snapshot, block);
+                return 1;
+            }
+            Decision::Action(action) => {
+                run_action(snapshot, &action, event.down);
+                return 1;
+            }
+        }
+    }
+    unsafe { CallNextHookEx(ptr::null_mut(), n_code, w_param, l_param) }
+}
 
-                    // Status keys to ignore if not part of the trigger
-                    let status_keys = [0x3A, 0x29, 0x7B, 0x79, 0x70];
+fn send_replay(snapshot: &Snapshot, replay: Vec<Replay>) {
+    for replay in replay {
+        match replay {
+            Replay::Key(scancode, down) => unsafe {
+                send_key_event(scancode, down, false);
+            },
+            Replay::Action(action, down) => run_action(snapshot, &action, down),
+        }
+    }
+}
 
-                    for (combo, block) in triggers.iter() {
-                        let is_all_held = combo.iter().all(|k| h_ref.contains(k));
-                        let is_last_key = combo.last() == Some(&actual_sc);
-                        let only_status_extras = h_ref
-                            .iter()
-                            .all(|k| combo.contains(k) || status_keys.contains(k));
+// Wake the message loop at `deadline`, so a dual-role key held past its
+// tapping term (or a sequence or chord left waiting) resolves without waiting
+// for the next key event
+fn schedule_poll(deadline: Option<Instant>) {
+    let old = POLL_TIMER.replace(0);
+    if old != 0 {
+        unsafe {
+            KillTimer(ptr::null_mut(), old);
+        }
+    }
+    if let Some(deadline) = deadline {
+        let ms = deadline
+            .saturating_duration_since(Instant::now())
+            .as_millis();
+        let id = unsafe { SetTimer(ptr::null_mut(), 0, ms.max(1) as u32, Some(on_poll_timer)) };
+        POLL_TIMER.set(id);
+    }
+}
 
-                        if is_all_held && is_last_key && only_status_extras {
-                            if let Some((best_combo, _)) = best_match {
-                                if combo.len() > best_combo.len() {
-                                    best_match = Some((combo, block));
-                                }
-                            } else {
-                                best_match = Some((combo, block));
-                            }
-                        }
-                    }
+unsafe extern "system" fn on_poll_timer(_hwnd: HWND, _msg: u32, _id: usize, _time: u32) {
+    let guard = SNAPSHOT.load();
+    let Some(snapshot) = guard.as_deref() else {
+        schedule_poll(None);
+        return;
+    };
+    let (replay, deadline) = ENGINE.with_borrow_mut(|engine| {
+        engine.poll(&snapshot.bindings, Instant::now());
+        (engine.take_replay(), engine.deadline())
+    });
+    send_replay(snapshot, replay);
+    schedule_poll(deadline);
+}
 
-                    if let (Some((_, block)), Some(executor_lock)) = (best_match, EXECUTOR.get())
-                        && let Some(executor) = executor_lock.read().unwrap().as_ref()
-                    {
-                        let exec = Arc::clone(executor);
-                        let b = block.clone();
-                        tokio::spawn(async move {
-                            exec.execute_block(&b).await;
-                        });
-                        return 1;
-                    }
+fn run_action(snapshot: &Snapshot, action: &KeyAction, down: bool) {
+    match action {
+        // Held as long as the key, so its modifiers go up with the key
+        KeyAction::Combo(keys) if down => {
+            for &scancode in keys {
+                unsafe {
+                    send_key_event(scancode, true, false);
                 }
-            } else {
-                h.remove(&actual_sc);
             }
         }
-
-        if let Some(profile_lock) = CURRENT_PROFILE.get()
-            && let Some(profile) = profile_lock.read().unwrap().as_ref()
-        {
-            let sc_str = format!("0x{:02X}", actual_sc);
-            if let Some(target_sc) = profile
-                .keys
-                .get(&sc_str)
-                .and_then(|name| profile::get_scancode(name))
-            {
+        KeyAction::Combo(keys) => {
+            for &scancode in keys.iter().rev() {
                 unsafe {
-                    send_key_event(target_sc, is_key_down, false);
+                    send_key_event(scancode, false, false);
                 }
-                return 1;
             }
         }
+        KeyAction::Text(text) if down => {
+            let text = text.clone();
+            tokio::spawn(async move {
+                for c in text.chars() {
+                    unsafe {
+                        send_unicode_char(c);
+                    }
+                }
+            });
+        }
+        KeyAction::Macro { name, args } if down => {
+            let exec = Arc::clone(&snapshot.executor);
+            let (name, args) = (name.clone(), args.clone());
+            tokio::spawn(async move {
+                if let Err(e) = exec.call_macro(&name, &args).await {
+                    eprintln!("Mapped key: {}", e);
+                }
+            });
+        }
+        // Text and macros are done on the press
+        KeyAction::Text(_) | KeyAction::Macro { .. } => {}
+    }
+}
+
+fn run_block(snapshot: &Snapshot, id: BlockId) {
+    if let Some((script, block)) = snapshot.blocks.get(id) {
+        let exec = Arc::clone(&snapshot.executor);
+        let (script, block) = (*script, block.clone());
+        tokio::spawn(async move {
+            exec.execute_block(script, &block).await;
+        });
     }
-    unsafe { CallNextHookEx(ptr::null_mut(), n_code, w_param, l_param) }
 }
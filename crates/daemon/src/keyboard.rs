@@ -1,7 +1,20 @@
 use dsl::TriggerKey;
+use profile::KeyNames;
 use windows_sys::Win32::UI::Input::KeyboardAndMouse::*;
 
-pub fn resolve_trigger_key(tk: &TriggerKey) -> Option<u16> {
+/// `Code_` keys are looked up in the active profile's `names`.
+pub fn resolve_trigger_key(tk: &TriggerKey, names: &KeyNames) -> Option<u16> {
+    match tk {
+        TriggerKey::Physical(sc) => Some(*sc),
+        TriggerKey::ExtendedPhysical(sc) => Some(*sc | 0xE000),
+        TriggerKey::Virtual(name) => names.get(name),
+    }
+}
+
+/// The scan code that makes the OS see the key `tk` names. Sent keys don't go
+/// through the profile's remaps, so `Code_` keys are looked up in the fixed
+/// table rather than in the profile's `names`.
+pub fn resolve_send_key(tk: &TriggerKey) -> Option<u16> {
     match tk {
         TriggerKey::Physical(sc) => Some(*sc),
         TriggerKey::ExtendedPhysical(sc) => Some(*sc | 0xE000),
@@ -32,6 +45,8 @@ pub unsafe fn send_unicode_char(c: char) {
 }
 
 pub unsafe fn send_key_event(scan_code: u16, is_key_down: bool, _is_sys_key: bool) {
+    // A generic modifier is sent as its left side
+    let scan_code = profile::sendable_scancode(scan_code);
     let mut input: INPUT = unsafe { std::mem::zeroed() };
     unsafe {
         input.r#type = INPUT_KEYBOARD;
@@ -1,34 +1,29 @@
 mod evaluator;
+mod events;
 mod hook;
 mod keyboard;
 mod simulator;
 mod state;
 mod tray;
 
-use std::collections::{BTreeSet, HashMap};
 use std::ptr;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::Arc;
+use std::time::Duration;
 use windows_sys::Win32::UI::WindowsAndMessaging::*;
 
 use crate::evaluator::KeyConditionEvaluator;
 use crate::hook::low_level_keyboard_proc;
 use crate::keyboard::resolve_trigger_key;
 use crate::simulator::WindowsInputSimulator;
-use crate::state::{CURRENT_PROFILE, EXECUTOR, HELD_KEYS, SCRIPT_TRIGGERS};
-use dsl::{Executor, Script};
-use profile::{Config, Profile};
+use crate::state::{HELD_KEYS, KEY_EVENTS, SNAPSHOT, Snapshot};
+use dsl::{Executor, Macro, ParseError, Spanned, Trigger, TriggerKey};
+use engine::{Bindings, KeyEvents};
+use profile::{Config, KeyMapping, KeyNames, Profile};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // 0. Initialize State Containers
-    HELD_KEYS
-        .set(Arc::new(Mutex::new(BTreeSet::new())))
-        .unwrap();
-    CURRENT_PROFILE.set(Arc::new(RwLock::new(None))).unwrap();
-    EXECUTOR.set(Arc::new(RwLock::new(None))).unwrap();
-    SCRIPT_TRIGGERS
-        .set(Arc::new(RwLock::new(HashMap::new())))
-        .unwrap();
+    KEY_EVENTS.set(KeyEvents::new()).unwrap();
 
     // 1. Load Config
     let config = Config::load_from_file("config.toml")?;
@@ -64,20 +59,20 @@ async fn main() -> anyhow::Result<()> {
             match tray::handle_tray_events() {
                 tray::TrayAction::Quit => break,
                 tray::TrayAction::Reload => {
-                    let current_name = {
-                        let p_lock = CURRENT_PROFILE.get().unwrap().read().unwrap();
-                        p_lock.as_ref().map(|p| p.name.clone())
-                    };
-                    if let Some(name) = current_name {
-                        let _ = load_profile(&config, &name).await;
+                    let current_name = SNAPSHOT.load().as_ref().map(|s| s.profile.name.clone());
+                    if let Some(name) = current_name
+                        && let Err(e) = load_profile(&config, &name).await
+                    {
+                        eprintln!("Failed to reload profile {}:\n{}", name, e);
                     }
                 }
-                tray::TrayAction::SwitchProfile(name) => {
-                    if load_profile(&config, &name).await.is_ok() {
+                tray::TrayAction::SwitchProfile(name) => match load_profile(&config, &name).await {
+                    Ok(()) => {
                         // Update tooltip
                         let _ = tray_icon.set_tooltip(Some(format!("phybkc - {}", name)));
                     }
-                }
+                    Err(e) => eprintln!("Failed to load profile {}:\n{}", name, e),
+                },
                 tray::TrayAction::None => {}
             }
         }
@@ -96,52 +91,120 @@ async fn load_profile(config: &Config, profile_name: &str) -> anyhow::Result<()>
     let profile = Profile::load_from_file(profile_path)?;
     println!("Loading profile: {}", profile.name);
 
-    let mut all_global_settings = Vec::new();
+    let mut scripts = Vec::new();
     let mut all_macros = Vec::new();
-    let mut triggers_map = HashMap::new();
+    let mut blocks = Vec::new();
+    let mut triggers = Vec::new();
+    let mut sequences = Vec::new();
+    let mut chords = Vec::new();
 
-    let held_keys = HELD_KEYS.get().unwrap().clone();
+    let key_events = KEY_EVENTS.get().unwrap().clone();
+    let names = Arc::new(profile.key_names());
 
     for script_path in &profile.scripts {
         println!("  Loading script: {}", script_path);
-        let content = std::fs::read_to_string(script_path)?;
-        let mut input = content.as_str();
-        let script = dsl::parse_script(&mut input)
-            .map_err(|e| anyhow::anyhow!("Parse error in {}: {:?}", script_path, e))?;
-
-        all_global_settings.extend(script.global_settings);
-        all_macros.extend(script.macros);
-
-        for block in script.blocks {
-            for combo in &block.triggers {
-                let mut resolved_combo = Vec::new();
-                for tk in &combo.0 {
-                    if let Some(sc) = resolve_trigger_key(tk) {
-                        resolved_combo.push(sc);
+    }
+    // Loaded together so that calls to the macros of another script are
+    // checked too
+    let loaded = dsl::load_scripts(&profile.scripts)?;
+
+    for (script_path, mut script) in profile.scripts.iter().zip(loaded) {
+        let index = scripts.len();
+
+        // Scripts importing the same file bring identical copies of its macros
+        for m in &script.macros {
+            if all_macros.contains(m) {
+                continue;
+            }
+            if all_macros.iter().any(|other: &Macro| other.name == m.name) {
+                eprintln!(
+                    "  Warning: macro `{}` in {} replaces an earlier definition",
+                    m.name, script_path
+                );
+            }
+            all_macros.push(m.clone());
+        }
+
+        for block in std::mem::take(&mut script.blocks) {
+            match &block.trigger {
+                Trigger::Keys(combos) => {
+                    // A step missing a key would fire on the wrong keys
+                    let mut steps = Vec::new();
+                    for combo in combos {
+                        steps.push(resolve_keys(&combo.0, &names, script_path)?);
+                    }
+                    if steps.len() == 1 {
+                        triggers.push((steps.remove(0), blocks.len()));
+                    } else {
+                        sequences.push((steps, blocks.len()));
                     }
                 }
-                triggers_map.insert(resolved_combo, block.clone());
+                Trigger::Chord(keys, window) => {
+                    // Never a smaller chord than the one written
+                    let keys = resolve_keys(keys, &names, script_path)?;
+                    chords.push((keys, Duration::from_millis(*window), blocks.len()));
+                }
             }
+            blocks.push((index, block));
         }
+        scripts.push(script);
     }
 
-    let consolidated_script = Script {
-        global_settings: all_global_settings,
-        macros: all_macros,
-        blocks: vec![],
-    };
+    // A key mapped to a macro no script defines would only fail when pressed
+    let mut mapped_macros = std::iter::once(&profile.keys)
+        .chain(profile.layers.iter().map(|layer| &layer.keys))
+        .flat_map(|keys| keys.values())
+        .filter_map(|mapping| match mapping {
+            KeyMapping::Macro(m) => Some(&m.name),
+            _ => None,
+        });
+    if let Some(name) = mapped_macros.find(|name| !all_macros.iter().any(|m| m.name == **name)) {
+        anyhow::bail!("A key is mapped to unknown macro `{}`", name);
+    }
 
     let executor = Arc::new(Executor::new(
-        consolidated_script,
+        scripts,
         Arc::new(WindowsInputSimulator),
-        Arc::new(KeyConditionEvaluator { held_keys }),
+        Arc::new(KeyConditionEvaluator {
+            held_keys: &HELD_KEYS,
+            names,
+            key_events,
+        }),
     ));
 
-    // Update global state
-    *CURRENT_PROFILE.get().unwrap().write().unwrap() = Some(profile);
-    *EXECUTOR.get().unwrap().write().unwrap() = Some(executor);
-    *SCRIPT_TRIGGERS.get().unwrap().write().unwrap() = triggers_map;
+    // Swap the new state in as a whole; the hook keeps using the old
+    // snapshot until its current event is done
+    let bindings = Bindings::new(triggers, profile.keymaps(), profile.ignored_scancodes())
+        .with_sequences(sequences, profile.sequence_timeout())
+        .with_chords(chords);
+    SNAPSHOT.store(Some(Arc::new(Snapshot {
+        profile,
+        bindings,
+        blocks,
+        executor,
+    })));
 
     println!("Profile {} loaded successfully.", profile_name);
     Ok(())
 }
+
+// The scan codes of the keys of a trigger, or an error pointing at the first
+// one the profile doesn't know
+fn resolve_keys(
+    keys: &[Spanned<TriggerKey>],
+    names: &KeyNames,
+    script_path: &str,
+) -> anyhow::Result<Vec<u16>> {
+    keys.iter()
+        .map(|tk| {
+            resolve_trigger_key(tk, names).ok_or_else(|| {
+                let TriggerKey::Virtual(name) = &tk.node else {
+                    unreachable!("scan codes always resolve");
+                };
+                let source = std::fs::read_to_string(script_path).unwrap_or_default();
+                let message = format!("unknown key `{}`", name);
+                ParseError::new(script_path, &source, tk.span.start, message).into()
+            })
+        })
+        .collect()
+}
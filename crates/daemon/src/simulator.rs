@@ -1,6 +1,6 @@
-use crate::keyboard::{resolve_trigger_key, send_key_event, send_unicode_char};
+use crate::keyboard::{resolve_send_key, send_key_event, send_unicode_char};
 use async_trait::async_trait;
-use dsl::{InputSimulator, SendExpression};
+use dsl::{InputSimulator, SendExpression, Spanned};
 use std::collections::BTreeSet;
 
 #[derive(Debug)]
@@ -8,12 +8,12 @@ pub struct WindowsInputSimulator;
 
 #[async_trait]
 impl InputSimulator for WindowsInputSimulator {
-    async fn send_keys(&self, expressions: &[SendExpression]) {
+    async fn send_keys(&self, expressions: &[Spanned<SendExpression>]) {
         let mut to_release = BTreeSet::new();
         for expr in expressions {
-            match expr {
+            match &expr.node {
                 SendExpression::Key(k) => {
-                    if let Some(sc) = resolve_trigger_key(k) {
+                    if let Some(sc) = resolve_send_key(k) {
                         unsafe {
                             send_key_event(sc, true, false);
                             tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
@@ -22,7 +22,7 @@ impl InputSimulator for WindowsInputSimulator {
                     }
                 }
                 SendExpression::Hold(k) => {
-                    if let Some(sc) = resolve_trigger_key(k) {
+                    if let Some(sc) = resolve_send_key(k) {
                         unsafe {
                             send_key_event(sc, true, false);
                         }
@@ -30,7 +30,7 @@ impl InputSimulator for WindowsInputSimulator {
                     }
                 }
                 SendExpression::Release(k) => {
-                    if let Some(sc) = resolve_trigger_key(k) {
+                    if let Some(sc) = resolve_send_key(k) {
                         unsafe {
                             send_key_event(sc, false, false);
                         }
@@ -47,7 +47,7 @@ impl InputSimulator for WindowsInputSimulator {
                 SendExpression::Combo(keys) => {
                     let mut scancodes = Vec::new();
                     for k in keys {
-                        if let Some(sc) = resolve_trigger_key(k) {
+                        if let Some(sc) = resolve_send_key(k) {
                             scancodes.push(sc);
                             unsafe {
                                 send_key_event(sc, true, false);
@@ -1,12 +1,23 @@
+use arc_swap::ArcSwapOption;
 use dsl::{Block, Executor};
+use engine::{Bindings, HeldKeys, KeyEvents};
 use profile::Profile;
-use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
 use std::sync::OnceLock;
-use std::sync::{Arc, Mutex, RwLock};
 
-pub type TriggerMap = HashMap<Vec<u16>, Block>;
+/// Everything the hook needs for the active profile. A reload builds a new
+/// one and swaps it in whole, so the hook never waits for a writer.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub profile: Profile,
+    pub bindings: Bindings,
+    /// Trigger blocks with the index of their script, indexed by
+    /// `engine::BlockId`
+    pub blocks: Vec<(usize, Block)>,
+    pub executor: Arc<Executor>,
+}
 
-pub static CURRENT_PROFILE: OnceLock<Arc<RwLock<Option<Profile>>>> = OnceLock::new();
-pub static HELD_KEYS: OnceLock<Arc<Mutex<BTreeSet<u16>>>> = OnceLock::new();
-pub static EXECUTOR: OnceLock<Arc<RwLock<Option<Arc<Executor>>>>> = OnceLock::new();
-pub static SCRIPT_TRIGGERS: OnceLock<Arc<RwLock<TriggerMap>>> = OnceLock::new();
+/// `None` until the first profile is loaded
+pub static SNAPSHOT: ArcSwapOption<Snapshot> = ArcSwapOption::const_empty();
+pub static HELD_KEYS: HeldKeys = HeldKeys::new();
+pub static KEY_EVENTS: OnceLock<KeyEvents> = OnceLock::new();
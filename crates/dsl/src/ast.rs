@@ -1,10 +1,15 @@
+pub use crate::span::{Span, Spanned};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Script {
-    pub global_settings: Vec<GlobalSetting>,
+    /// `import "...";` paths as written, relative to this file
+    pub imports: Vec<Spanned<String>>,
+    pub global_settings: Vec<Spanned<GlobalSetting>>,
+    pub variables: Vec<Spanned<Variable>>,
     pub macros: Vec<Macro>,
     pub blocks: Vec<Block>,
+    pub span: Span,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -12,23 +17,51 @@ pub enum GlobalSetting {
     Cli(String),
 }
 
+// `let name = "...";` at top level (global) or inside a body (block-local)
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Variable {
+    pub name: String,
+    pub value: String,
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Macro {
     pub name: String,
-    pub body: Vec<Statement>,
+    /// Bound as local variables to the call's arguments
+    pub params: Vec<String>,
+    pub body: Vec<Spanned<Statement>>,
+    pub span: Span,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Block {
-    pub triggers: Vec<TriggerCombinations>,
-    pub body: Vec<Statement>,
+    pub trigger: Trigger,
+    pub body: Vec<Spanned<Statement>>,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum Trigger {
+    /// Pressed one after another (`Code_G -> Code_G`); usually a single
+    /// combination
+    Keys(Vec<TriggerCombinations>),
+    /// `chord(Code_J, Code_K, 50)`: all keys go down, in any order, within
+    /// the time in milliseconds
+    Chord(Vec<Spanned<TriggerKey>>, u64),
 }
 
 // A trigger can be a single key or a combination (e.g. #0x01 + Code_A)
 // In design.md: #0x02 + Code_A
 // We can represent this as a list of keys required to be active.
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
-pub struct TriggerCombinations(pub Vec<TriggerKey>);
+pub struct TriggerCombinations(pub Vec<Spanned<TriggerKey>>);
+
+impl TriggerCombinations {
+    /// Same keys in the same order, wherever they were written.
+    pub fn same_keys(&self, other: &TriggerCombinations) -> bool {
+        self.0.len() == other.0.len() && self.0.iter().zip(&other.0).all(|(a, b)| a.node == b.node)
+    }
+}
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum TriggerKey {
@@ -43,25 +76,54 @@ pub enum Statement {
     Execute(String),
     TryRun {
         command: String,
-        failure: Option<Box<Statement>>, // Can be FailRun or FailExecute
+        failure: Option<Box<Spanned<Statement>>>, // Can be FailRun or FailExecute
     },
     TryExecute {
         command: String,
-        failure: Option<Box<Statement>>,
+        failure: Option<Box<Spanned<Statement>>>,
     },
-    Send(Vec<SendExpression>),
+    Send(Vec<Spanned<SendExpression>>),
     Wait(u64),
     If {
-        condition: Condition,
-        then_branch: Vec<Statement>,
-        else_if_branches: Vec<(Condition, Vec<Statement>)>,
-        else_branch: Option<Vec<Statement>>,
+        condition: Spanned<Condition>,
+        then_branch: Vec<Spanned<Statement>>,
+        else_if_branches: Vec<(Spanned<Condition>, Vec<Spanned<Statement>>)>,
+        else_branch: Option<Vec<Spanned<Statement>>>,
     },
     Loop {
-        count: usize,
-        body: Vec<Statement>,
+        count: Option<usize>, // None loops until `break`
+        body: Vec<Spanned<Statement>>,
+    },
+    While {
+        condition: Spanned<Condition>,
+        body: Vec<Spanned<Statement>>,
     },
-    MacroCall(String),
+    Break,
+    Continue,
+    MacroCall {
+        name: String,
+        args: Vec<String>,
+    },
+    Let(Variable),
+    /// `match wait_input(A, B) { A => {...} timeout => {...} }`
+    Match {
+        condition: Spanned<Condition>,
+        arms: Vec<MatchArm>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct MatchArm {
+    pub pattern: Spanned<MatchPattern>,
+    pub body: Vec<Spanned<Statement>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum MatchPattern {
+    /// One of the alternatives of the matched condition
+    Keys(TriggerCombinations),
+    /// None of the alternatives fired (timed out, aborted or not held)
+    Timeout,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -71,6 +133,24 @@ pub enum Condition {
     NowInput(Vec<TriggerCombinations>),
     WaitReleased(Vec<TriggerCombinations>),
     WaitReleasedTime(Vec<TriggerCombinations>, u64),
+    And(Box<Spanned<Condition>>, Box<Spanned<Condition>>),
+    Or(Box<Spanned<Condition>>, Box<Spanned<Condition>>),
+    Not(Box<Spanned<Condition>>),
+}
+
+impl Condition {
+    /// The key combinations a key condition waits for or checks; empty for
+    /// `and`/`or`/`not`.
+    pub fn alternatives(&self) -> &[TriggerCombinations] {
+        match self {
+            Condition::WaitInput(combos)
+            | Condition::WaitInputTime(combos, _)
+            | Condition::NowInput(combos)
+            | Condition::WaitReleased(combos)
+            | Condition::WaitReleasedTime(combos, _) => combos,
+            Condition::And(..) | Condition::Or(..) | Condition::Not(_) => &[],
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
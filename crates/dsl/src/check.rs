@@ -0,0 +1,336 @@
+// Load-time checks that need more than the grammar (variable scoping, loop control, ...)
+
+use crate::ast::*;
+use crate::interpolate::variable_refs;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug)]
+pub(crate) struct CheckError {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Scope<'a> {
+    variables: HashSet<&'a str>,
+    /// Whether `break`/`continue` have a loop to target
+    in_loop: bool,
+    /// Parameter counts of the macros defined in this file or imported
+    arities: HashMap<&'a str, usize>,
+}
+
+/// `imported` holds the scripts brought in by `import`; their items are
+/// visible here but were checked when their own file was loaded.
+pub(crate) fn check_script(script: &Script, imported: &[&Script]) -> Result<(), CheckError> {
+    // Globals are visible in every macro and block, wherever they are declared
+    let mut globals = Scope::default();
+    for other in imported {
+        globals
+            .variables
+            .extend(other.variables.iter().map(|v| v.name.as_str()));
+        globals.arities.extend(
+            other
+                .macros
+                .iter()
+                .map(|m| (m.name.as_str(), m.params.len())),
+        );
+    }
+    for variable in &script.variables {
+        check_text(&variable.value, &globals, variable.span)?;
+        globals.variables.insert(&variable.name);
+    }
+    for m in &script.macros {
+        globals.arities.insert(&m.name, m.params.len());
+    }
+    for m in &script.macros {
+        let mut params = HashSet::new();
+        if let Some(dup) = m.params.iter().find(|p| !params.insert(p.as_str())) {
+            return Err(CheckError {
+                span: m.span,
+                message: format!("duplicate parameter `{}` in macro `{}`", dup, m.name),
+            });
+        }
+        let mut scope = globals.clone();
+        scope.variables.extend(params);
+        check_body(&m.body, &scope)?;
+    }
+    for block in &script.blocks {
+        if let Trigger::Chord(keys, _) = &block.trigger {
+            check_chord(keys, block.span)?;
+        }
+        check_body(&block.body, &globals)?;
+    }
+    Ok(())
+}
+
+fn check_chord(keys: &[Spanned<TriggerKey>], span: Span) -> Result<(), CheckError> {
+    if keys.len() < 2 {
+        return Err(CheckError {
+            span,
+            message: "a chord needs at least two keys".to_string(),
+        });
+    }
+    for (i, key) in keys.iter().enumerate() {
+        if keys[..i].iter().any(|k| k.node == key.node) {
+            return Err(CheckError {
+                span: key.span,
+                message: "duplicate key in chord".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn check_body<'a>(body: &'a [Spanned<Statement>], outer: &Scope<'a>) -> Result<(), CheckError> {
+    let mut scope = outer.clone();
+    for stmt in body {
+        check_statement(stmt, &mut scope)?;
+    }
+    Ok(())
+}
+
+fn check_statement<'a>(
+    stmt: &'a Spanned<Statement>,
+    scope: &mut Scope<'a>,
+) -> Result<(), CheckError> {
+    match &stmt.node {
+        Statement::Run(text) | Statement::Execute(text) => check_text(text, scope, stmt.span),
+        Statement::TryRun { command, failure } | Statement::TryExecute { command, failure } => {
+            check_text(command, scope, stmt.span)?;
+            match failure {
+                Some(f) => check_statement(f, scope),
+                None => Ok(()),
+            }
+        }
+        Statement::Send(exprs) => {
+            for expr in exprs {
+                if let SendExpression::String(text) = &expr.node {
+                    check_text(text, scope, expr.span)?;
+                }
+            }
+            Ok(())
+        }
+        Statement::If {
+            then_branch,
+            else_if_branches,
+            else_branch,
+            ..
+        } => {
+            check_body(then_branch, scope)?;
+            for (_, body) in else_if_branches {
+                check_body(body, scope)?;
+            }
+            match else_branch {
+                Some(body) => check_body(body, scope),
+                None => Ok(()),
+            }
+        }
+        Statement::Match { condition, arms } => {
+            let mut seen: Vec<&MatchPattern> = Vec::new();
+            for arm in arms {
+                if let MatchPattern::Keys(keys) = &arm.pattern.node
+                    && !condition
+                        .alternatives()
+                        .iter()
+                        .any(|alt| alt.same_keys(keys))
+                {
+                    return Err(CheckError {
+                        span: arm.pattern.span,
+                        message: "pattern is not one of the keys of the matched condition"
+                            .to_string(),
+                    });
+                }
+                if seen.iter().any(|p| same_pattern(p, &arm.pattern)) {
+                    return Err(CheckError {
+                        span: arm.pattern.span,
+                        message: "unreachable match arm: this pattern is already handled"
+                            .to_string(),
+                    });
+                }
+                seen.push(&arm.pattern);
+                check_body(&arm.body, scope)?;
+            }
+            Ok(())
+        }
+        Statement::Loop { body, .. } | Statement::While { body, .. } => {
+            let inner = Scope {
+                in_loop: true,
+                ..scope.clone()
+            };
+            check_body(body, &inner)
+        }
+        Statement::Break | Statement::Continue if !scope.in_loop => {
+            let keyword = if matches!(stmt.node, Statement::Break) {
+                "break"
+            } else {
+                "continue"
+            };
+            Err(CheckError {
+                span: stmt.span,
+                message: format!("`{}` outside of a loop", keyword),
+            })
+        }
+        Statement::Let(variable) => {
+            check_text(&variable.value, scope, stmt.span)?;
+            scope.variables.insert(&variable.name);
+            Ok(())
+        }
+        Statement::MacroCall { name, args } => {
+            // Macros from other scripts of the profile can't be checked here
+            // (`load_scripts` does once they are all loaded)
+            if let Some(&arity) = scope.arities.get(name.as_str())
+                && arity != args.len()
+            {
+                return Err(arity_mismatch(name, arity, args.len(), stmt.span));
+            }
+            for arg in args {
+                check_text(arg, scope, stmt.span)?;
+            }
+            Ok(())
+        }
+        Statement::Wait(_) | Statement::Break | Statement::Continue => Ok(()),
+    }
+}
+
+fn arity_mismatch(name: &str, arity: usize, given: usize, span: Span) -> CheckError {
+    CheckError {
+        span,
+        message: format!(
+            "macro `{}` takes {} argument(s) but {} were given",
+            name, arity, given
+        ),
+    }
+}
+
+/// Check every macro call in `macros` and `blocks` against `arities`, the
+/// parameter counts of all the macros of a profile.
+pub(crate) fn check_macro_calls(
+    macros: &[Macro],
+    blocks: &[Block],
+    arities: &HashMap<&str, usize>,
+) -> Result<(), CheckError> {
+    let bodies = macros
+        .iter()
+        .map(|m| &m.body)
+        .chain(blocks.iter().map(|b| &b.body));
+    for body in bodies {
+        check_calls(body, arities)?;
+    }
+    Ok(())
+}
+
+fn check_calls(
+    body: &[Spanned<Statement>],
+    arities: &HashMap<&str, usize>,
+) -> Result<(), CheckError> {
+    for stmt in body {
+        check_call(stmt, arities)?;
+    }
+    Ok(())
+}
+
+fn check_call(stmt: &Spanned<Statement>, arities: &HashMap<&str, usize>) -> Result<(), CheckError> {
+    match &stmt.node {
+        Statement::MacroCall { name, args } => match arities.get(name.as_str()) {
+            None => Err(CheckError {
+                span: stmt.span,
+                message: format!("unknown macro `{}`", name),
+            }),
+            Some(&arity) if arity != args.len() => {
+                Err(arity_mismatch(name, arity, args.len(), stmt.span))
+            }
+            Some(_) => Ok(()),
+        },
+        Statement::TryRun { failure, .. } | Statement::TryExecute { failure, .. } => {
+            match failure {
+                Some(f) => check_call(f, arities),
+                None => Ok(()),
+            }
+        }
+        Statement::If {
+            then_branch,
+            else_if_branches,
+            else_branch,
+            ..
+        } => {
+            check_calls(then_branch, arities)?;
+            for (_, body) in else_if_branches {
+                check_calls(body, arities)?;
+            }
+            match else_branch {
+                Some(body) => check_calls(body, arities),
+                None => Ok(()),
+            }
+        }
+        Statement::Match { arms, .. } => {
+            for arm in arms {
+                check_calls(&arm.body, arities)?;
+            }
+            Ok(())
+        }
+        Statement::Loop { body, .. } | Statement::While { body, .. } => check_calls(body, arities),
+        _ => Ok(()),
+    }
+}
+
+fn same_pattern(a: &MatchPattern, b: &MatchPattern) -> bool {
+    match (a, b) {
+        (MatchPattern::Keys(a), MatchPattern::Keys(b)) => a.same_keys(b),
+        (MatchPattern::Timeout, MatchPattern::Timeout) => true,
+        _ => false,
+    }
+}
+
+fn check_text(text: &str, scope: &Scope<'_>, span: Span) -> Result<(), CheckError> {
+    match variable_refs(text).find(|name| !scope.variables.contains(name)) {
+        Some(name) => Err(CheckError {
+            span,
+            message: format!("unknown variable `{}`", name),
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::parse_source;
+
+    #[test]
+    fn test_unknown_variable_is_rejected() {
+        let source = "Code_F1 {\n    if now_input(Code_A) {\n        let x = \"1\";\n    }\n    Run: \"${x}\";\n}";
+        let err = parse_source(source, "test.phybkc").expect_err("`x` is out of scope");
+        assert_eq!(err.message, "unknown variable `x`");
+        assert_eq!(err.line, 5);
+    }
+
+    #[test]
+    fn test_break_outside_loop_is_rejected() {
+        let source = "macro M {\n    loop 2 {\n        wait(1);\n    }\n    break;\n}";
+        let err = parse_source(source, "test.phybkc").expect_err("`break` needs a loop");
+        assert_eq!(err.message, "`break` outside of a loop");
+        assert_eq!((err.line, err.column), (5, 5));
+    }
+
+    #[test]
+    fn test_macro_arity_is_checked() {
+        let source = "macro M(a) {\n    wait(1);\n}\nCode_F1 {\n    M!;\n}";
+        let err = parse_source(source, "test.phybkc").expect_err("`M` needs one argument");
+        assert_eq!(
+            err.message,
+            "macro `M` takes 1 argument(s) but 0 were given"
+        );
+        assert_eq!((err.line, err.column), (5, 5));
+    }
+
+    #[test]
+    fn test_match_pattern_must_be_an_alternative() {
+        let source =
+            "Code_F1 {\n    match wait_input(Code_Y, Code_N) {\n        Code_Q => {}\n    }\n}";
+        let err = parse_source(source, "test.phybkc").expect_err("`Code_Q` is not waited for");
+        assert_eq!(
+            err.message,
+            "pattern is not one of the keys of the matched condition"
+        );
+        assert_eq!((err.line, err.column), (3, 9));
+    }
+}
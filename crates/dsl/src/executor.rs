@@ -1,4 +1,5 @@
 use crate::ast::*;
+use crate::interpolate::interpolate;
 use async_trait::async_trait;
 use futures::FutureExt;
 use futures::future::BoxFuture;
@@ -10,17 +11,85 @@ use tokio::time::{Duration, sleep};
 
 #[async_trait]
 pub trait InputSimulator: Send + Sync + fmt::Debug {
-    async fn send_keys(&self, expressions: &[SendExpression]);
+    async fn send_keys(&self, expressions: &[Spanned<SendExpression>]);
 }
 
 #[async_trait]
 pub trait ConditionEvaluator: Send + Sync + fmt::Debug {
     async fn evaluate(&self, condition: &Condition) -> bool;
+
+    /// Index into `condition.alternatives()` of the combination that made the
+    /// condition true, for `match`. The default can only tell that *some*
+    /// alternative fired and reports the first one.
+    async fn select(&self, condition: &Condition) -> Option<usize> {
+        self.evaluate(condition).await.then_some(0)
+    }
+}
+
+/// Variables visible to a running body. Nested bodies work on a copy, so a
+/// `let` inside a block never leaks out of it.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    variables: HashMap<String, String>,
+}
+
+impl Scope {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.variables.get(name).map(String::as_str)
+    }
+
+    pub fn set(&mut self, name: &str, value: String) {
+        self.variables.insert(name.to_string(), value);
+    }
+
+    /// Expand `${name}` references in `text`.
+    pub fn interpolate(&self, text: &str) -> String {
+        interpolate(text, |name| self.get(name))
+    }
+}
+
+/// Why [`Executor::call_macro`] could not run a macro.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacroCallError {
+    Unknown(String),
+    Arity {
+        name: String,
+        expected: usize,
+        given: usize,
+    },
+}
+
+impl fmt::Display for MacroCallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MacroCallError::Unknown(name) => write!(f, "unknown macro `{}`", name),
+            MacroCallError::Arity {
+                name,
+                expected,
+                given,
+            } => write!(
+                f,
+                "macro `{}` takes {} argument(s) but {} were given",
+                name, expected, given
+            ),
+        }
+    }
+}
+
+/// How a statement finished; `break`/`continue` unwind to the nearest loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Normal,
+    Break,
+    Continue,
 }
 
 pub struct Executor {
     cli: Option<String>,
-    macros: HashMap<String, Vec<Statement>>,
+    /// Globals of each script, in the order the scripts were given
+    globals: Vec<Scope>,
+    /// With the index of the script they belong to
+    macros: HashMap<String, (usize, Macro)>,
     input_sim: Arc<dyn InputSimulator>,
     cond_eval: Arc<dyn ConditionEvaluator>,
 }
@@ -29,6 +98,7 @@ impl fmt::Debug for Executor {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Executor")
             .field("cli", &self.cli)
+            .field("globals", &self.globals)
             .field("macros", &self.macros)
             .field("input_sim", &self.input_sim)
             .field("cond_eval", &self.cond_eval)
@@ -37,43 +107,118 @@ impl fmt::Debug for Executor {
 }
 
 impl Executor {
+    /// `scripts` are the scripts of a profile. Each keeps its own globals, so
+    /// a block or macro only sees the `let`s of its own file and its imports.
+    /// Macros are shared: a later script's macro replaces an earlier one of
+    /// the same name, unless it is the same definition imported again.
     pub fn new(
-        script: Script,
+        scripts: Vec<Script>,
         input_sim: Arc<dyn InputSimulator>,
         cond_eval: Arc<dyn ConditionEvaluator>,
     ) -> Self {
-        let mut macros = HashMap::new();
-        for m in script.macros {
-            macros.insert(m.name, m.body);
-        }
+        let mut macros: HashMap<String, (usize, Macro)> = HashMap::new();
+        let mut globals = Vec::new();
         let mut cli = None;
-        for setting in script.global_settings {
-            match setting {
-                GlobalSetting::Cli(val) => cli = Some(val),
+        for (index, script) in scripts.into_iter().enumerate() {
+            for m in script.macros {
+                if macros.get(&m.name).is_some_and(|(_, other)| *other == m) {
+                    continue;
+                }
+                macros.insert(m.name.clone(), (index, m));
+            }
+            let mut scope = Scope::default();
+            for variable in script.variables {
+                let value = scope.interpolate(&variable.value);
+                scope.set(&variable.name, value);
+            }
+            globals.push(scope);
+            for setting in script.global_settings {
+                match setting.node {
+                    GlobalSetting::Cli(val) => cli = Some(val),
+                }
             }
         }
         Self {
             cli,
+            globals,
             macros,
             input_sim,
             cond_eval,
         }
     }
 
-    pub async fn execute_block(&self, block: &Block) {
-        self.execute_statements(&block.body).await;
+    /// Evaluate `and`/`or`/`not` here with short-circuiting; only the key
+    /// conditions at the leaves reach the `ConditionEvaluator`.
+    pub fn evaluate_condition<'a>(&'a self, condition: &'a Condition) -> BoxFuture<'a, bool> {
+        async move {
+            match condition {
+                Condition::And(lhs, rhs) => {
+                    self.evaluate_condition(lhs).await && self.evaluate_condition(rhs).await
+                }
+                Condition::Or(lhs, rhs) => {
+                    self.evaluate_condition(lhs).await || self.evaluate_condition(rhs).await
+                }
+                Condition::Not(inner) => !self.evaluate_condition(inner).await,
+                leaf => self.cond_eval.evaluate(leaf).await,
+            }
+        }
+        .boxed()
+    }
+
+    /// Run a block of the `script`-th script.
+    pub async fn execute_block(&self, script: usize, block: &Block) {
+        let mut scope = self.globals.get(script).cloned().unwrap_or_default();
+        self.execute_statements(&block.body, &mut scope).await;
     }
 
-    pub async fn execute_statements(&self, statements: &[Statement]) {
+    /// Run the macro `name` as `name!(...)` would, with `args` already
+    /// expanded. Used for keys mapped to a macro in a profile.
+    pub async fn call_macro(&self, name: &str, args: &[String]) -> Result<(), MacroCallError> {
+        let (script, m) = self
+            .macros
+            .get(name)
+            .ok_or_else(|| MacroCallError::Unknown(name.to_string()))?;
+        // Arity is only checked at load time within one file
+        if m.params.len() != args.len() {
+            return Err(MacroCallError::Arity {
+                name: name.to_string(),
+                expected: m.params.len(),
+                given: args.len(),
+            });
+        }
+        // Macros see the globals of their script and their parameters, not
+        // the caller's locals
+        let mut macro_scope = self.globals[*script].clone();
+        for (param, arg) in m.params.iter().zip(args) {
+            macro_scope.set(param, arg.clone());
+        }
+        self.execute_statements(&m.body, &mut macro_scope).await;
+        Ok(())
+    }
+
+    pub async fn execute_statements(
+        &self,
+        statements: &[Spanned<Statement>],
+        scope: &mut Scope,
+    ) -> Flow {
         for stmt in statements {
-            self.execute_statement(stmt).await;
+            let flow = self.execute_statement(stmt, scope).await;
+            if flow != Flow::Normal {
+                return flow;
+            }
         }
+        Flow::Normal
     }
 
-    pub fn execute_statement<'a>(&'a self, stmt: &'a Statement) -> BoxFuture<'a, ()> {
+    pub fn execute_statement<'a>(
+        &'a self,
+        stmt: &'a Spanned<Statement>,
+        scope: &'a mut Scope,
+    ) -> BoxFuture<'a, Flow> {
         async move {
-            match stmt {
+            match &stmt.node {
                 Statement::Run(cmd) => {
+                    let cmd = &scope.interpolate(cmd);
                     let cli = self.cli.as_deref().unwrap_or("cmd");
                     match cli.to_lowercase().as_str() {
                         "powershell" | "pwsh" => {
@@ -94,9 +239,10 @@ impl Executor {
                     }
                 }
                 Statement::Execute(cmd) => {
-                    let _ = Command::new(cmd).spawn();
+                    let _ = Command::new(scope.interpolate(cmd)).spawn();
                 }
                 Statement::TryRun { command, failure } => {
+                    let command = &scope.interpolate(command);
                     let cli = self.cli.as_deref().unwrap_or("cmd");
                     let success = match cli.to_lowercase().as_str() {
                         "powershell" | "pwsh" => Command::new("powershell")
@@ -112,21 +258,31 @@ impl Executor {
                     };
 
                     if let (false, Some(f)) = (success, failure) {
-                        self.execute_statement(f).await;
+                        self.execute_statement(f, scope).await;
                     }
                 }
                 Statement::TryExecute { command, failure } => {
-                    match Command::new(command).status() {
+                    match Command::new(scope.interpolate(command)).status() {
                         Ok(status) if status.success() => {}
                         _ => {
                             if let Some(f) = failure {
-                                self.execute_statement(f).await;
+                                self.execute_statement(f, scope).await;
                             }
                         }
                     }
                 }
                 Statement::Send(exprs) => {
-                    self.input_sim.send_keys(exprs).await;
+                    let exprs: Vec<Spanned<SendExpression>> = exprs
+                        .iter()
+                        .map(|expr| match &expr.node {
+                            SendExpression::String(text) => Spanned::new(
+                                SendExpression::String(scope.interpolate(text)),
+                                expr.span,
+                            ),
+                            _ => expr.clone(),
+                        })
+                        .collect();
+                    self.input_sim.send_keys(&exprs).await;
                 }
                 Statement::Wait(ms) => {
                     sleep(Duration::from_millis(*ms)).await;
@@ -137,35 +293,317 @@ impl Executor {
                     else_if_branches,
                     else_branch,
                 } => {
-                    if self.cond_eval.evaluate(condition).await {
-                        self.execute_statements(then_branch).await;
-                    } else {
-                        let mut matched = false;
-                        for (c, b) in else_if_branches {
-                            if self.cond_eval.evaluate(c).await {
-                                self.execute_statements(b).await;
-                                matched = true;
-                                break;
-                            }
+                    if self.evaluate_condition(condition).await {
+                        return self.execute_statements(then_branch, &mut scope.clone()).await;
+                    }
+                    for (c, b) in else_if_branches {
+                        if self.evaluate_condition(c).await {
+                            return self.execute_statements(b, &mut scope.clone()).await;
                         }
-                        if !matched
-                            && let Some(b) = else_branch {
-                                self.execute_statements(b).await;
-                            }
+                    }
+                    if let Some(b) = else_branch {
+                        return self.execute_statements(b, &mut scope.clone()).await;
                     }
                 }
                 Statement::Loop { count, body } => {
-                    for _ in 0..*count {
-                        self.execute_statements(body).await;
+                    let mut iteration = 0;
+                    while count.is_none_or(|n| iteration < n) {
+                        iteration += 1;
+                        if count.is_none() {
+                            // Don't starve other scripts when the body never waits
+                            tokio::task::yield_now().await;
+                        }
+                        if self.execute_statements(body, &mut scope.clone()).await == Flow::Break {
+                            break;
+                        }
+                    }
+                }
+                Statement::While { condition, body } => {
+                    while self.evaluate_condition(condition).await {
+                        tokio::task::yield_now().await;
+                        if self.execute_statements(body, &mut scope.clone()).await == Flow::Break {
+                            break;
+                        }
+                    }
+                }
+                Statement::Match { condition, arms } => {
+                    let pattern = match self.cond_eval.select(condition).await {
+                        Some(i) => condition.alternatives().get(i),
+                        None => None,
+                    };
+                    let arm = arms.iter().find(|arm| match (&arm.pattern.node, pattern) {
+                        (MatchPattern::Keys(keys), Some(fired)) => keys.same_keys(fired),
+                        (MatchPattern::Timeout, None) => true,
+                        _ => false,
+                    });
+                    if let Some(arm) = arm {
+                        return self.execute_statements(&arm.body, &mut scope.clone()).await;
                     }
                 }
-                Statement::MacroCall(name) => {
-                    if let Some(body) = self.macros.get(name) {
-                        self.execute_statements(body).await;
+                Statement::Break => return Flow::Break,
+                Statement::Continue => return Flow::Continue,
+                Statement::MacroCall { name, args } => {
+                    let args: Vec<String> = args.iter().map(|arg| scope.interpolate(arg)).collect();
+                    if let Err(e) = self.call_macro(name, &args).await {
+                        eprintln!("{}:{}: {}", stmt.span.line, stmt.span.column, e);
                     }
                 }
+                Statement::Let(variable) => {
+                    let value = scope.interpolate(&variable.value);
+                    scope.set(&variable.name, value);
+                }
             }
+            Flow::Normal
         }
         .boxed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_source;
+    use std::sync::Mutex;
+
+    // Records every `Send: String(...)` so tests can observe execution
+    #[derive(Debug, Default)]
+    struct RecordingSimulator {
+        sent: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl InputSimulator for RecordingSimulator {
+        async fn send_keys(&self, expressions: &[Spanned<SendExpression>]) {
+            for expr in expressions {
+                if let SendExpression::String(s) = &expr.node {
+                    self.sent.lock().unwrap().push(s.clone());
+                }
+            }
+        }
+    }
+
+    // `now_input(Code_X, Code_Y)` picks the first alternative whose first key
+    // is in `held`; every key looked at is logged
+    #[derive(Debug, Default)]
+    struct HeldKeysEvaluator {
+        held: Vec<String>,
+        queried: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl ConditionEvaluator for HeldKeysEvaluator {
+        async fn evaluate(&self, condition: &Condition) -> bool {
+            self.select(condition).await.is_some()
+        }
+
+        async fn select(&self, condition: &Condition) -> Option<usize> {
+            let Condition::NowInput(combos) = condition else {
+                return None;
+            };
+            combos.iter().position(|combo| {
+                let TriggerKey::Virtual(name) = &combo.0[0].node else {
+                    return false;
+                };
+                self.queried.lock().unwrap().push(name.clone());
+                self.held.contains(name)
+            })
+        }
+    }
+
+    struct Run {
+        sent: Vec<String>,
+        queried: Vec<String>,
+    }
+
+    async fn run_first_block(source: &str, held: &[&str]) -> Run {
+        let script = parse_source(source, "test.phybkc").expect("Should parse");
+        let block = script.blocks[0].clone();
+        let sim = Arc::new(RecordingSimulator::default());
+        let eval = Arc::new(HeldKeysEvaluator {
+            held: held.iter().map(|k| k.to_string()).collect(),
+            ..Default::default()
+        });
+        let executor = Executor::new(vec![script], sim.clone(), eval.clone());
+        executor.execute_block(0, &block).await;
+        Run {
+            sent: sim.sent.lock().unwrap().clone(),
+            queried: eval.queried.lock().unwrap().clone(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_global_and_local_variables() {
+        let run = run_first_block(
+            r#"
+            let dir = r"C:\tools";
+            let app = "${dir}\\app.exe";
+            Code_F1 {
+                Send: String("${app}");
+                let dir = "D:";
+                Send: String("${dir}");
+                if now_input(Code_A) {
+                    let inner = "${dir}/inner";
+                    Send: String("${inner}");
+                }
+                SHOW!;
+            }
+            macro SHOW {
+                Send: String("${dir}");
+            }
+            "#,
+            &["A"],
+        )
+        .await;
+        assert_eq!(
+            run.sent,
+            [r"C:\tools\app.exe", "D:", "D:/inner", r"C:\tools"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_condition_combinators_short_circuit() {
+        let run = run_first_block(
+            r#"
+            Code_F1 {
+                if now_input(Code_B) and now_input(Code_C) {
+                    Send: String("and");
+                }
+                if now_input(Code_A) or now_input(Code_C) {
+                    Send: String("or");
+                }
+                if not now_input(Code_B) and (now_input(Code_B) or now_input(Code_A)) {
+                    Send: String("nested");
+                }
+            }
+            "#,
+            &["A"],
+        )
+        .await;
+        assert_eq!(run.sent, ["or", "nested"]);
+        assert_eq!(run.queried, ["B", "A", "B", "B", "A"]);
+    }
+
+    #[tokio::test]
+    async fn test_loop_control_flow() {
+        let run = run_first_block(
+            r#"
+            Code_F1 {
+                loop 2 {
+                    loop {
+                        Send: String("inner");
+                        if now_input(Code_A) {
+                            break;
+                        }
+                    }
+                    Send: String("outer");
+                    continue;
+                    Send: String("unreachable");
+                }
+                while now_input(Code_B) {
+                    Send: String("never");
+                }
+            }
+            "#,
+            &["A"],
+        )
+        .await;
+        assert_eq!(run.sent, ["inner", "outer", "inner", "outer"]);
+    }
+
+    #[tokio::test]
+    async fn test_macro_arguments() {
+        let run = run_first_block(
+            r#"
+            let editor = "code";
+            macro OpenIn(app, file) {
+                Send: String("${app} ${file}");
+                Send: String("${editor}");
+            }
+            Code_F1 {
+                let name = "notes";
+                OpenIn!("${editor}", "${name}.md");
+                OpenIn!(r"C:\vim", "");
+            }
+            "#,
+            &[],
+        )
+        .await;
+        assert_eq!(run.sent, ["code notes.md", "code", r"C:\vim ", "code"]);
+    }
+
+    #[tokio::test]
+    async fn test_globals_belong_to_their_script() {
+        let parse = |source| parse_source(source, "test.phybkc").expect("Should parse");
+        let first = parse(
+            "let x = \"first\";\nmacro ShowX {\n    Send: String(\"${x}\");\n}\nCode_F1 {\n    Send: String(\"${x}\");\n}",
+        );
+        let second =
+            parse("let x = \"second\";\nCode_F2 {\n    Send: String(\"${x}\");\n    ShowX!;\n}");
+        let blocks = [first.blocks[0].clone(), second.blocks[0].clone()];
+        let sim = Arc::new(RecordingSimulator::default());
+        let executor = Executor::new(
+            vec![first, second],
+            sim.clone(),
+            Arc::new(HeldKeysEvaluator::default()),
+        );
+        executor.execute_block(0, &blocks[0]).await;
+        executor.execute_block(1, &blocks[1]).await;
+        // The macro sees the `x` of the script defining it
+        assert_eq!(*sim.sent.lock().unwrap(), ["first", "second", "first"]);
+    }
+
+    #[tokio::test]
+    async fn test_call_macro_by_name() {
+        let script = parse_source(
+            "macro Greet(who) {\n    Send: String(\"hi ${who}\");\n}",
+            "test.phybkc",
+        )
+        .expect("Should parse");
+        let sim = Arc::new(RecordingSimulator::default());
+        let executor = Executor::new(
+            vec![script],
+            sim.clone(),
+            Arc::new(HeldKeysEvaluator::default()),
+        );
+        assert_eq!(
+            executor.call_macro("Greet", &["you".to_string()]).await,
+            Ok(())
+        );
+        assert_eq!(*sim.sent.lock().unwrap(), ["hi you"]);
+        assert_eq!(
+            executor.call_macro("Greet", &[]).await,
+            Err(MacroCallError::Arity {
+                name: "Greet".to_string(),
+                expected: 1,
+                given: 0
+            })
+        );
+        assert_eq!(
+            executor
+                .call_macro("Nope", &[])
+                .await
+                .unwrap_err()
+                .to_string(),
+            "unknown macro `Nope`"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_match_runs_the_arm_of_the_fired_alternative() {
+        let source = r#"
+            Code_F1 {
+                match now_input(Code_Y, Code_N, Code_Shift + Code_Q) {
+                    Code_N => { Send: String("no"); }
+                    Code_Y => { Send: String("yes"); }
+                    timeout => { Send: String("none"); }
+                }
+            }
+        "#;
+        assert_eq!(run_first_block(source, &["N"]).await.sent, ["no"]);
+        assert_eq!(
+            run_first_block(source, &["Shift"]).await.sent,
+            Vec::<String>::new()
+        );
+        assert_eq!(run_first_block(source, &[]).await.sent, ["none"]);
+    }
+}
@@ -0,0 +1,102 @@
+// `${name}` interpolation in string arguments. `$${` writes a literal `${`.
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Piece<'a> {
+    Text(&'a str),
+    Variable(&'a str),
+}
+
+pub(crate) fn pieces(text: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while let Some(pos) = rest.find("${") {
+        if rest[..pos].ends_with('$') {
+            pieces.push(Piece::Text(&rest[..pos - 1]));
+            pieces.push(Piece::Text("${"));
+            rest = &rest[pos + 2..];
+            continue;
+        }
+        let after = &rest[pos + 2..];
+        let Some(end) = after.find('}') else {
+            break;
+        };
+        let name = &after[..end];
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            // Not a variable reference, keep it verbatim
+            pieces.push(Piece::Text(&rest[..pos + 2]));
+            rest = after;
+            continue;
+        }
+        pieces.push(Piece::Text(&rest[..pos]));
+        pieces.push(Piece::Variable(name));
+        rest = &after[end + 1..];
+    }
+    pieces.push(Piece::Text(rest));
+    pieces.retain(|p| *p != Piece::Text(""));
+    pieces
+}
+
+/// Names referenced by `${...}` in `text`.
+pub(crate) fn variable_refs(text: &str) -> impl Iterator<Item = &str> {
+    pieces(text).into_iter().filter_map(|p| match p {
+        Piece::Variable(name) => Some(name),
+        Piece::Text(_) => None,
+    })
+}
+
+/// Substitute `${...}` using `lookup`; unknown names are left as written.
+pub(crate) fn interpolate<'v>(text: &str, lookup: impl Fn(&str) -> Option<&'v str>) -> String {
+    let mut out = String::with_capacity(text.len());
+    for piece in pieces(text) {
+        match piece {
+            Piece::Text(t) => out.push_str(t),
+            Piece::Variable(name) => match lookup(name) {
+                Some(value) => out.push_str(value),
+                None => {
+                    out.push_str("${");
+                    out.push_str(name);
+                    out.push('}');
+                }
+            },
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<&'static str> {
+        match name {
+            "dir" => Some(r"C:\tools"),
+            "app" => Some("code"),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_interpolate_variables() {
+        assert_eq!(
+            interpolate(r"${dir}\${app}.exe", lookup),
+            r"C:\tools\code.exe"
+        );
+        assert_eq!(interpolate("no vars", lookup), "no vars");
+    }
+
+    #[test]
+    fn test_interpolate_escape_and_unknown() {
+        assert_eq!(interpolate("$${dir} ${dir}", lookup), r"${dir} C:\tools");
+        assert_eq!(interpolate("${missing}", lookup), "${missing}");
+        assert_eq!(
+            interpolate("$env:PATH ${ not a var } ${", lookup),
+            "$env:PATH ${ not a var } ${"
+        );
+    }
+
+    #[test]
+    fn test_variable_refs() {
+        let refs: Vec<&str> = variable_refs("${a} $${b} ${c}").collect();
+        assert_eq!(refs, ["a", "c"]);
+    }
+}
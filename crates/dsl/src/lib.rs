@@ -1,7 +1,14 @@
 pub mod ast;
+mod check;
+pub mod error;
 pub mod executor;
+mod interpolate;
+pub mod loader;
 pub mod parser;
+pub mod span;
 
 pub use ast::*;
+pub use error::ParseError;
 pub use executor::*;
-pub use parser::parse_script;
+pub use loader::{LoadError, load_script, load_scripts, load_source};
+pub use parser::{parse_script, parse_source};
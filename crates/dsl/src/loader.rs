@@ -0,0 +1,386 @@
+// Resolving `import "...";` across script files
+
+use crate::ast::*;
+use crate::check::{check_macro_calls, check_script};
+use crate::error::ParseError;
+use crate::parser::parse_source_unchecked;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    #[error("failed to read {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+}
+
+/// Read a script file and resolve its imports, see [`load_source`].
+pub fn load_script(path: impl AsRef<Path>) -> Result<Script, LoadError> {
+    let path = path.as_ref();
+    Ok(load_source(&read_file(path)?, path)?)
+}
+
+/// Load the scripts of a profile, each as [`load_script`] would.
+///
+/// As the scripts share their macros, calls are also checked across them:
+/// every macro called must be defined in one of the scripts (or their
+/// imports) and given as many arguments as it has parameters. When two
+/// scripts define the same macro, the later definition is the one checked
+/// against.
+pub fn load_scripts<P: AsRef<Path>>(paths: &[P]) -> Result<Vec<Script>, LoadError> {
+    let mut loader = Loader::default();
+    let mut roots = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        let key = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let root = match loader.by_path.get(&key) {
+            Some(&index) => index,
+            None => loader.load(read_file(path)?, path.display().to_string(), key)?,
+        };
+        roots.push(root);
+    }
+    let scripts: Vec<Script> = roots.iter().map(|&root| loader.assemble(root)).collect();
+
+    let mut arities = HashMap::new();
+    for m in scripts.iter().flat_map(|s| &s.macros) {
+        arities.insert(m.name.as_str(), m.params.len());
+    }
+    // Only the blocks of the scripts themselves run, not those of imports
+    for (index, file) in loader.files.iter().enumerate() {
+        let blocks: &[Block] = if roots.contains(&index) {
+            &file.script.blocks
+        } else {
+            &[]
+        };
+        check_macro_calls(&file.script.macros, blocks, &arities)
+            .map_err(|e| ParseError::new(&file.name, &file.source, e.span.start, e.message))?;
+    }
+    Ok(scripts)
+}
+
+fn read_file(path: &Path) -> Result<String, LoadError> {
+    std::fs::read_to_string(path).map_err(|source| LoadError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Parse `source` as the contents of `path`, resolving imports relative to it.
+///
+/// The settings, variables and macros of every imported file (and of their
+/// imports) come before the file's own, while `blocks` only holds the blocks
+/// of `source`. A file imported more than once is only loaded once.
+pub fn load_source(source: &str, path: &Path) -> Result<Script, ParseError> {
+    let mut loader = Loader::default();
+    let key = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let root = loader.load(source.to_string(), path.display().to_string(), key)?;
+    Ok(loader.assemble(root))
+}
+
+struct File {
+    name: String,
+    source: String,
+    script: Script,
+    /// Every file imported directly or indirectly, dependencies first
+    deps: Vec<usize>,
+}
+
+#[derive(Default)]
+struct Loader {
+    files: Vec<File>,
+    by_path: HashMap<PathBuf, usize>,
+    /// Canonical path and name of the files currently being loaded
+    stack: Vec<(PathBuf, String)>,
+}
+
+impl Loader {
+    /// The `root`-th file with the items of its imports, see [`load_source`]
+    fn assemble(&self, root: usize) -> Script {
+        let root = &self.files[root];
+        let mut script = root.script.clone();
+        let files: Vec<&Script> = root
+            .deps
+            .iter()
+            .map(|&i| &self.files[i].script)
+            .chain([&root.script])
+            .collect();
+        script.global_settings = files
+            .iter()
+            .flat_map(|s| s.global_settings.iter().cloned())
+            .collect();
+        script.variables = files
+            .iter()
+            .flat_map(|s| s.variables.iter().cloned())
+            .collect();
+        script.macros = files
+            .iter()
+            .flat_map(|s| s.macros.iter().cloned())
+            .collect();
+        script
+    }
+
+    fn load(&mut self, source: String, name: String, key: PathBuf) -> Result<usize, ParseError> {
+        let script = parse_source_unchecked(&source, &name)?;
+        let dir = Path::new(&name)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        self.stack.push((key.clone(), name.clone()));
+        let mut deps: Vec<usize> = Vec::new();
+        // Macro name -> index of the file defining it (`None` for this one)
+        let mut origins: HashMap<String, Option<usize>> = HashMap::new();
+        // Global name -> index of the imported file defining it. Imported
+        // macros read the globals as their own file defines them, so
+        // another value for the same name can't be allowed here.
+        let mut globals: HashMap<String, usize> = HashMap::new();
+        for import in &script.imports {
+            let error =
+                |message: String| ParseError::new(&name, &source, import.span.start, message);
+            let path = dir.join(&import.node);
+            let canonical = path
+                .canonicalize()
+                .map_err(|e| error(format!("cannot import `{}`: {}", import.node, e)))?;
+            if let Some(pos) = self.stack.iter().position(|(p, _)| *p == canonical) {
+                let chain: Vec<&str> = self.stack[pos..]
+                    .iter()
+                    .map(|(_, n)| n.as_str())
+                    .chain([path.to_str().unwrap_or(&import.node)])
+                    .collect();
+                return Err(error(format!("import cycle: {}", chain.join(" -> "))));
+            }
+            let index = match self.by_path.get(&canonical) {
+                Some(&index) => index,
+                None => {
+                    let imported_source = std::fs::read_to_string(&path)
+                        .map_err(|e| error(format!("cannot import `{}`: {}", import.node, e)))?;
+                    self.load(imported_source, path.display().to_string(), canonical)?
+                }
+            };
+
+            let new_files: Vec<usize> = self.files[index]
+                .deps
+                .iter()
+                .copied()
+                .chain([index])
+                .filter(|i| !deps.contains(i))
+                .collect();
+            for &i in &new_files {
+                for m in &self.files[i].script.macros {
+                    if let Some(&Some(other)) = origins.get(m.name.as_str()) {
+                        return Err(error(format!(
+                            "macro `{}` from {} is already defined in {}",
+                            m.name, self.files[i].name, self.files[other].name
+                        )));
+                    }
+                    origins.insert(m.name.clone(), Some(i));
+                }
+                for v in &self.files[i].script.variables {
+                    if let Some(&other) = globals.get(v.name.as_str())
+                        && other != i
+                    {
+                        return Err(error(format!(
+                            "global `{}` from {} is already defined in {}",
+                            v.name, self.files[i].name, self.files[other].name
+                        )));
+                    }
+                    globals.insert(v.name.clone(), i);
+                }
+            }
+            deps.extend(new_files);
+        }
+        self.stack.pop();
+
+        for m in &script.macros {
+            if let Some(&origin) = origins.get(m.name.as_str()) {
+                let defined_in = origin.map_or("this file", |i| self.files[i].name.as_str());
+                return Err(ParseError::new(
+                    &name,
+                    &source,
+                    m.span.start,
+                    format!("macro `{}` is already defined in {}", m.name, defined_in),
+                ));
+            }
+            origins.insert(m.name.clone(), None);
+        }
+        for v in &script.variables {
+            if let Some(&origin) = globals.get(v.name.as_str()) {
+                return Err(ParseError::new(
+                    &name,
+                    &source,
+                    v.span.start,
+                    format!(
+                        "global `{}` is already defined in {}",
+                        v.name, self.files[origin].name
+                    ),
+                ));
+            }
+        }
+
+        let imported: Vec<&Script> = deps.iter().map(|&i| &self.files[i].script).collect();
+        check_script(&script, &imported)
+            .map_err(|e| ParseError::new(&name, &source, e.span.start, e.message))?;
+
+        self.files.push(File {
+            name,
+            source,
+            script,
+            deps,
+        });
+        self.by_path.insert(key, self.files.len() - 1);
+        Ok(self.files.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes `files` into a fresh directory under the system temp dir
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("phybkc-loader-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (name, content) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_imports_are_resolved_relative_to_the_importing_file() {
+        let dir = write_files(
+            "relative",
+            &[
+                (
+                    "main.phybkc",
+                    "import \"lib/common.phybkc\";\nimport \"lib/keys.phybkc\";\nCode_F1 {\n    OpenIn!(\"${editor}\");\n}",
+                ),
+                (
+                    "lib/common.phybkc",
+                    "CLI = \"pwsh\";\nlet editor = \"code\";\nmacro OpenIn(app) {\n    Execute: \"${app}\";\n}",
+                ),
+                // Reaches common.phybkc a second time through another path
+                (
+                    "lib/keys.phybkc",
+                    "import \"common.phybkc\";\nmacro Enter {\n    Send: Code_Enter;\n}",
+                ),
+            ],
+        );
+        let script = load_script(dir.join("main.phybkc")).expect("Should load imports");
+        let names: Vec<&str> = script.macros.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["OpenIn", "Enter"]);
+        assert_eq!(script.variables.len(), 1);
+        assert_eq!(script.global_settings.len(), 1);
+        assert_eq!(script.blocks.len(), 1);
+    }
+
+    #[test]
+    fn test_import_cycle_is_rejected() {
+        let dir = write_files(
+            "cycle",
+            &[
+                ("a.phybkc", "import \"b.phybkc\";"),
+                ("b.phybkc", "\nimport \"a.phybkc\";"),
+            ],
+        );
+        let Err(LoadError::Parse(err)) = load_script(dir.join("a.phybkc")) else {
+            panic!("Expected an import cycle error");
+        };
+        assert!(err.message.starts_with("import cycle: "), "{}", err.message);
+        assert!(err.file.ends_with("b.phybkc"));
+        assert_eq!((err.line, err.column), (2, 1));
+    }
+
+    #[test]
+    fn test_duplicate_macro_across_imports_is_rejected() {
+        let dir = write_files(
+            "duplicate",
+            &[
+                ("main.phybkc", "import \"a.phybkc\";\nimport \"b.phybkc\";"),
+                ("a.phybkc", "macro M {\n    wait(1);\n}"),
+                ("b.phybkc", "macro M {\n    wait(2);\n}"),
+            ],
+        );
+        let Err(LoadError::Parse(err)) = load_script(dir.join("main.phybkc")) else {
+            panic!("Expected a duplicate macro error");
+        };
+        assert!(
+            err.message.starts_with("macro `M` from "),
+            "{}",
+            err.message
+        );
+        assert_eq!((err.line, err.column), (2, 1));
+    }
+
+    #[test]
+    fn test_global_shadowing_an_import_is_rejected() {
+        let dir = write_files(
+            "shadowing",
+            &[
+                (
+                    "main.phybkc",
+                    "import \"common.phybkc\";\nlet dir = \"b\";\nCode_F1 {\n    M!;\n}",
+                ),
+                (
+                    "common.phybkc",
+                    "let dir = \"a\";\nmacro M {\n    Send: String(\"${dir}\");\n}",
+                ),
+            ],
+        );
+        let Err(LoadError::Parse(err)) = load_script(dir.join("main.phybkc")) else {
+            panic!("Expected a shadowing error");
+        };
+        assert!(
+            err.message
+                .starts_with("global `dir` is already defined in "),
+            "{}",
+            err.message
+        );
+        assert!(err.file.ends_with("main.phybkc"));
+        assert_eq!((err.line, err.column), (2, 1));
+    }
+
+    #[test]
+    fn test_macro_calls_are_checked_across_scripts() {
+        let dir = write_files(
+            "across",
+            &[
+                (
+                    "lib.phybkc",
+                    "macro OpenIn(app) {\n    Execute: \"${app}\";\n}",
+                ),
+                ("good.phybkc", "Code_F1 {\n    OpenIn!(\"code\");\n}"),
+                ("arity.phybkc", "Code_F2 {\n    wait(1);\n    OpenIn!;\n}"),
+                ("unknown.phybkc", "macro M {\n    Missing!;\n}"),
+            ],
+        );
+        let scripts = load_scripts(&[dir.join("lib.phybkc"), dir.join("good.phybkc")])
+            .expect("Should load a call to another script's macro");
+        assert_eq!(scripts.len(), 2);
+
+        let Err(LoadError::Parse(err)) =
+            load_scripts(&[dir.join("lib.phybkc"), dir.join("arity.phybkc")])
+        else {
+            panic!("Expected an arity error");
+        };
+        assert_eq!(
+            err.message,
+            "macro `OpenIn` takes 1 argument(s) but 0 were given"
+        );
+        assert!(err.file.ends_with("arity.phybkc"));
+        assert_eq!((err.line, err.column), (3, 5));
+
+        let Err(LoadError::Parse(err)) = load_scripts(&[dir.join("unknown.phybkc")]) else {
+            panic!("Expected an unknown macro error");
+        };
+        assert_eq!(err.message, "unknown macro `Missing`");
+    }
+}
@@ -1,14 +1,62 @@
 use crate::ast::*;
+use crate::check::check_script;
+use crate::error::ParseError;
+use crate::span::LineIndex;
 use winnow::ascii::{alphanumeric1, digit1, hex_digit1, multispace0, multispace1};
-use winnow::combinator::{alt, delimited, eof, opt, preceded, repeat, separated, seq, terminated};
-use winnow::error::ModalResult;
+use winnow::combinator::{
+    alt, cut_err, delimited, eof, not, opt, peek, preceded, repeat, separated, seq, terminated,
+};
+use winnow::error::{ContextError, ErrMode, ModalResult, StrContext, StrContextValue};
 use winnow::prelude::*;
-use winnow::token::{take_till, take_while};
+use winnow::stream::{AsChar, LocatingSlice, Location, Stateful, Stream};
+use winnow::token::{any, take_till, take_until, take_while};
 
 type PResult<O> = ModalResult<O>;
 
+// Input that tracks byte offsets and carries the line index for spans
+type Input<'a> = Stateful<LocatingSlice<&'a str>, &'a LineIndex<'a>>;
+
+// Context attached to committed (cut) parsers, rendered as "expected ..."
+fn expected(what: &'static str) -> StrContext {
+    StrContext::Expected(StrContextValue::Description(what))
+}
+
+/// Parse a whole script, turning failures into a [`ParseError`] that carries
+/// the file name, line/column and the offending source line.
+///
+/// Imports are not resolved; use [`crate::load_source`] for that.
+pub fn parse_source(source: &str, file_name: &str) -> Result<Script, ParseError> {
+    let script = parse_source_unchecked(source, file_name)?;
+    check_script(&script, &[])
+        .map_err(|e| ParseError::new(file_name, source, e.span.start, e.message))?;
+    Ok(script)
+}
+
+// Grammar only; the loader runs the checks once imports are known
+pub(crate) fn parse_source_unchecked(source: &str, file_name: &str) -> Result<Script, ParseError> {
+    let mut input = source;
+    parse_script.parse_next(&mut input).map_err(|e| {
+        let offset = source.len() - input.len();
+        let message = e
+            .into_inner()
+            .map(|inner| error_message(&inner))
+            .unwrap_or_else(|_| "unexpected end of input".to_string());
+        ParseError::new(file_name, source, offset, message)
+    })
+}
+
+fn error_message(err: &ContextError) -> String {
+    // Contexts are pushed while unwinding, so the first one is the innermost
+    err.context()
+        .find_map(|c| match c {
+            StrContext::Expected(value) => Some(format!("expected {}", value)),
+            _ => None,
+        })
+        .unwrap_or_else(|| "invalid syntax".to_string())
+}
+
 // Utility to skip whitespace and comments
-fn ws(input: &mut &str) -> PResult<()> {
+fn ws(input: &mut Input<'_>) -> PResult<()> {
     loop {
         let start_len = input.len();
         let _ = multispace0.parse_next(input)?;
@@ -23,85 +71,230 @@ fn ws(input: &mut &str) -> PResult<()> {
     Ok(())
 }
 
+// Statement terminator. On failure the input is rewound past the skipped
+// whitespace so the error points right after the previous token.
+fn terminator<'a>(what: &'static str) -> impl FnMut(&mut Input<'a>) -> PResult<()> {
+    move |input: &mut Input<'a>| {
+        let before = input.checkpoint();
+        ws(input)?;
+        if opt(';').parse_next(input)?.is_some() {
+            return Ok(());
+        }
+        input.reset(&before);
+        let mut err = ContextError::new();
+        err.push(expected(what));
+        Err(ErrMode::Cut(err))
+    }
+}
+
+// Attach the span of whatever `parser` consumed
+fn spanned<'a, O>(
+    mut parser: impl Parser<Input<'a>, O, ErrMode<ContextError>>,
+) -> impl Parser<Input<'a>, Spanned<O>, ErrMode<ContextError>> {
+    move |input: &mut Input<'a>| {
+        let (node, range) = parser.by_ref().with_span().parse_next(input)?;
+        Ok(Spanned::new(node, input.state.span(range)))
+    }
+}
+
 // Top-level parser
 pub fn parse_script(input: &mut &str) -> PResult<Script> {
-    let (global_settings, macros, blocks) = seq!(
+    let source = *input;
+    let lines = LineIndex::new(source);
+    let mut located = Stateful {
+        input: LocatingSlice::new(source),
+        state: &lines,
+    };
+    let result = parse_script_items(&mut located);
+    // Leave the caller's input where parsing stopped, as a plain `&str` parser would
+    *input = &source[located.current_token_start()..];
+    result
+}
+
+fn parse_script_items(input: &mut Input<'_>) -> PResult<Script> {
+    let items: Vec<Item> = seq!(
         _: ws,
-        repeat(0.., terminated(parse_global_setting, ws)),
-        repeat(0.., terminated(parse_macro, ws)),
-        repeat(0.., terminated(parse_block, ws)),
-        _: eof
+        repeat(0.., terminated(parse_item, ws)),
+        _: cut_err(eof).context(expected("an `import`, `CLI` setting, `macro` definition or trigger block"))
     )
+    .map(|(items,)| items)
     .parse_next(input)?;
 
-    Ok(Script {
-        global_settings,
-        macros,
-        blocks,
-    })
+    let mut script = Script {
+        imports: Vec::new(),
+        global_settings: Vec::new(),
+        variables: Vec::new(),
+        macros: Vec::new(),
+        blocks: Vec::new(),
+        span: input.state.span(0..input.current_token_start()),
+    };
+    for item in items {
+        match item {
+            Item::Import(path) => script.imports.push(path),
+            Item::Setting(setting) => script.global_settings.push(setting),
+            Item::Variable(variable) => script.variables.push(variable),
+            Item::Macro(m) => script.macros.push(m),
+            Item::Block(block) => script.blocks.push(block),
+        }
+    }
+    Ok(script)
+}
+
+// Top-level items may appear in any order
+enum Item {
+    Import(Spanned<String>),
+    Setting(Spanned<GlobalSetting>),
+    Variable(Spanned<Variable>),
+    Macro(Macro),
+    Block(Block),
+}
+
+fn parse_item(input: &mut Input<'_>) -> PResult<Item> {
+    alt((
+        spanned(parse_import).map(Item::Import),
+        spanned(parse_global_setting).map(Item::Setting),
+        spanned(parse_variable).map(Item::Variable),
+        parse_macro.map(Item::Macro),
+        parse_block.map(Item::Block),
+    ))
+    .parse_next(input)
+}
+
+// import "common.phybkc";
+fn parse_import(input: &mut Input<'_>) -> PResult<String> {
+    seq!(
+        _: keyword("import"),
+        _: ws,
+        cut_err(parse_string_literal).context(expected("a file path after `import`")),
+        _: terminator("`;` after import")
+    )
+    .map(|(path,)| path)
+    .parse_next(input)
 }
 
 // Global Settings
-fn parse_global_setting(input: &mut &str) -> PResult<GlobalSetting> {
+fn parse_global_setting(input: &mut Input<'_>) -> PResult<GlobalSetting> {
     seq!(
         _: "CLI",
         _: ws,
         _: "=",
         _: ws,
-        alt((
+        cut_err(alt((
             parse_string_literal,
             take_till(1.., ';').map(|s: &str| s.trim().to_string())
-        )),
-        _: ws,
-        _: ";"
+        )))
+        .context(expected("a shell name after `CLI =`")),
+        _: terminator("`;` after CLI setting")
     )
     .map(|(val,)| GlobalSetting::Cli(val))
     .parse_next(input)
 }
 
-fn parse_identifier(input: &mut &str) -> PResult<String> {
+// Variables
+fn parse_variable(input: &mut Input<'_>) -> PResult<Variable> {
+    seq!(
+        _: "let",
+        _: multispace1,
+        cut_err(parse_identifier).context(expected("variable name after `let`")),
+        _: ws,
+        _: cut_err("=").context(expected("`=` after variable name")),
+        _: ws,
+        cut_err(parse_string_literal).context(expected("string literal after `=`")),
+        _: terminator("`;` after let statement")
+    )
+    .map(|(name, value)| Variable { name, value })
+    .parse_next(input)
+}
+
+fn parse_identifier(input: &mut Input<'_>) -> PResult<String> {
     take_while(1.., |c: char| c.is_alphanumeric() || c == '_')
         .map(String::from)
         .parse_next(input)
 }
 
 // Macros
-fn parse_macro(input: &mut &str) -> PResult<Macro> {
-    seq!(
+fn parse_macro(input: &mut Input<'_>) -> PResult<Macro> {
+    let ((name, params, body), range) = seq!(
         _: "macro",
         _: multispace1,
-        parse_identifier,
+        cut_err(parse_identifier).context(expected("macro name")),
+        _: ws,
+        opt(parse_macro_params).map(Option::unwrap_or_default),
         _: ws,
-        parse_body_block
+        cut_err(parse_body_block)
+    )
+    .with_span()
+    .parse_next(input)?;
+    Ok(Macro {
+        name,
+        params,
+        body,
+        span: input.state.span(range),
+    })
+}
+
+// (a, b)
+fn parse_macro_params(input: &mut Input<'_>) -> PResult<Vec<String>> {
+    delimited(
+        ("(", ws),
+        separated(0.., parse_identifier, (ws, ",", ws)),
+        (ws, cut_err(")").context(expected("parameter name or `)`"))),
     )
-    .map(|(name, body)| Macro { name, body })
     .parse_next(input)
 }
 
 // Blocks
-fn parse_block(input: &mut &str) -> PResult<Block> {
-    let triggers = separated(1.., parse_trigger_combinations, (ws, "+", ws)).parse_next(input)?;
-    let body = parse_body_block(input)?;
-    Ok(Block { triggers, body })
+fn parse_block(input: &mut Input<'_>) -> PResult<Block> {
+    let ((trigger, body), range) = (
+        alt((
+            parse_chord,
+            // A sequence: `Code_Space -> Code_F` or `Code_G, Code_G`
+            separated(1.., parse_trigger_combinations, (ws, alt(("->", ",")), ws))
+                .map(Trigger::Keys),
+        )),
+        cut_err(parse_body_block),
+    )
+        .with_span()
+        .parse_next(input)?;
+    Ok(Block {
+        trigger,
+        body,
+        span: input.state.span(range),
+    })
+}
+
+// chord(Code_J, Code_K, 50)
+fn parse_chord(input: &mut Input<'_>) -> PResult<Trigger> {
+    seq!(
+        _: keyword("chord"), _: ws, _: "(", _: ws,
+        cut_err(repeat(1.., terminated(spanned(parse_trigger_key), (ws, ",", ws))))
+            .context(expected("keys and `,` inside `chord(...)`")),
+        cut_err(digit1).context(expected("time window in milliseconds")),
+        _: ws, _: cut_err(")").context(expected("`)` to close `chord(`"))
+    )
+    .map(|(keys, window): (Vec<Spanned<TriggerKey>>, &str)| {
+        Trigger::Chord(keys, window.parse().unwrap_or(0))
+    })
+    .parse_next(input)
 }
 
-fn parse_body_block(input: &mut &str) -> PResult<Vec<Statement>> {
+fn parse_body_block(input: &mut Input<'_>) -> PResult<Vec<Spanned<Statement>>> {
     delimited(
-        (ws, "{", ws),
+        (ws, "{".context(expected("`{`")), ws),
         repeat(0.., terminated(parse_statement, ws)),
-        (ws, "}"),
+        (ws, cut_err("}").context(expected("a statement or `}`"))),
     )
     .parse_next(input)
 }
 
-fn parse_trigger_combinations(input: &mut &str) -> PResult<TriggerCombinations> {
-    let keys: Vec<TriggerKey> =
-        separated(1.., parse_trigger_key, (ws, "+", ws)).parse_next(input)?;
+fn parse_trigger_combinations(input: &mut Input<'_>) -> PResult<TriggerCombinations> {
+    let keys: Vec<Spanned<TriggerKey>> =
+        separated(1.., spanned(parse_trigger_key), (ws, "+", ws)).parse_next(input)?;
     Ok(TriggerCombinations(keys))
 }
 
 // Keys
-fn parse_trigger_key(input: &mut &str) -> PResult<TriggerKey> {
+fn parse_trigger_key(input: &mut Input<'_>) -> PResult<TriggerKey> {
     alt((
         parse_extended_physical_key,
         parse_physical_key,
@@ -110,27 +303,27 @@ fn parse_trigger_key(input: &mut &str) -> PResult<TriggerKey> {
     .parse_next(input)
 }
 
-fn parse_extended_physical_key(input: &mut &str) -> PResult<TriggerKey> {
+fn parse_extended_physical_key(input: &mut Input<'_>) -> PResult<TriggerKey> {
     ("#E0/0x", hex_digit1)
         .map(|(_, hex)| TriggerKey::ExtendedPhysical(u16::from_str_radix(hex, 16).unwrap_or(0)))
         .parse_next(input)
 }
 
-fn parse_physical_key(input: &mut &str) -> PResult<TriggerKey> {
+fn parse_physical_key(input: &mut Input<'_>) -> PResult<TriggerKey> {
     ("#0x", hex_digit1)
         .map(|(_, hex)| TriggerKey::Physical(u16::from_str_radix(hex, 16).unwrap_or(0)))
         .parse_next(input)
 }
 
-fn parse_virtual_key(input: &mut &str) -> PResult<TriggerKey> {
+fn parse_virtual_key(input: &mut Input<'_>) -> PResult<TriggerKey> {
     alt((preceded("Code_", alphanumeric1), alphanumeric1))
         .map(|s: &str| TriggerKey::Virtual(s.to_string()))
         .parse_next(input)
 }
 
 // Statements
-fn parse_statement(input: &mut &str) -> PResult<Statement> {
-    alt((
+fn parse_statement(input: &mut Input<'_>) -> PResult<Spanned<Statement>> {
+    spanned(alt((
         parse_try_run,
         parse_try_execute,
         parse_run,
@@ -138,39 +331,44 @@ fn parse_statement(input: &mut &str) -> PResult<Statement> {
         parse_send,
         parse_wait_stmt,
         parse_if,
+        parse_match,
         parse_loop,
+        parse_while,
+        parse_break,
+        parse_continue,
+        parse_variable.map(Statement::Let),
         parse_macro_call,
-    ))
+    )))
     .parse_next(input)
 }
 
-fn parse_run(input: &mut &str) -> PResult<Statement> {
+fn parse_run(input: &mut Input<'_>) -> PResult<Statement> {
     seq!(
         _: "Run", _: ws, _: ":", _: ws,
-        parse_string_literal,
-        _: ws, _: ";"
+        cut_err(parse_string_literal).context(expected("string literal after `Run:`")),
+        _: terminator("`;` after Run statement")
     )
     .map(|(val,)| Statement::Run(val))
     .parse_next(input)
 }
 
-fn parse_execute(input: &mut &str) -> PResult<Statement> {
+fn parse_execute(input: &mut Input<'_>) -> PResult<Statement> {
     seq!(
         _: "Execute", _: ws, _: ":", _: ws,
-        parse_string_literal,
-        _: ws, _: ";"
+        cut_err(parse_string_literal).context(expected("string literal after `Execute:`")),
+        _: terminator("`;` after Execute statement")
     )
     .map(|(val,)| Statement::Execute(val))
     .parse_next(input)
 }
 
-fn parse_try_run(input: &mut &str) -> PResult<Statement> {
+fn parse_try_run(input: &mut Input<'_>) -> PResult<Statement> {
     seq!(
         _: "TryRun", _: ws, _: ":", _: ws,
-        parse_string_literal,
-        _: ws, _: ":", _: ws,
-        parse_fail_stmt,
-        _: ws, _: ";"
+        cut_err(parse_string_literal).context(expected("string literal after `TryRun:`")),
+        _: ws, _: cut_err(":").context(expected("`:` before the failure action")), _: ws,
+        cut_err(spanned(parse_fail_stmt)).context(expected("`FailRun:` or `FailExecute:`")),
+        _: terminator("`;` after TryRun statement")
     )
     .map(|(cmd, fallback)| Statement::TryRun {
         command: cmd,
@@ -179,13 +377,13 @@ fn parse_try_run(input: &mut &str) -> PResult<Statement> {
     .parse_next(input)
 }
 
-fn parse_try_execute(input: &mut &str) -> PResult<Statement> {
+fn parse_try_execute(input: &mut Input<'_>) -> PResult<Statement> {
     seq!(
         _: "TryExecute", _: ws, _: ":", _: ws,
-        parse_string_literal,
-        _: ws, _: ":", _: ws,
-        parse_fail_stmt,
-        _: ws, _: ";"
+        cut_err(parse_string_literal).context(expected("string literal after `TryExecute:`")),
+        _: ws, _: cut_err(":").context(expected("`:` before the failure action")), _: ws,
+        cut_err(spanned(parse_fail_stmt)).context(expected("`FailRun:` or `FailExecute:`")),
+        _: terminator("`;` after TryExecute statement")
     )
     .map(|(cmd, fallback)| Statement::TryExecute {
         command: cmd,
@@ -194,53 +392,54 @@ fn parse_try_execute(input: &mut &str) -> PResult<Statement> {
     .parse_next(input)
 }
 
-fn parse_fail_stmt(input: &mut &str) -> PResult<Statement> {
+fn parse_fail_stmt(input: &mut Input<'_>) -> PResult<Statement> {
     alt((parse_fail_run, parse_fail_execute)).parse_next(input)
 }
 
-fn parse_fail_run(input: &mut &str) -> PResult<Statement> {
+fn parse_fail_run(input: &mut Input<'_>) -> PResult<Statement> {
     seq!(
         _: "FailRun", _: ws, _: ":", _: ws,
-        parse_string_literal
+        cut_err(parse_string_literal).context(expected("string literal after `FailRun:`"))
     )
     .map(|(val,)| Statement::Run(val))
     .parse_next(input)
 }
 
-fn parse_fail_execute(input: &mut &str) -> PResult<Statement> {
+fn parse_fail_execute(input: &mut Input<'_>) -> PResult<Statement> {
     seq!(
         _: "FailExecute", _: ws, _: ":", _: ws,
-        parse_string_literal
+        cut_err(parse_string_literal).context(expected("string literal after `FailExecute:`"))
     )
     .map(|(val,)| Statement::Execute(val))
     .parse_next(input)
 }
 
-fn parse_send(input: &mut &str) -> PResult<Statement> {
+fn parse_send(input: &mut Input<'_>) -> PResult<Statement> {
     seq!(
         _: "Send", _: ws, _: ":", _: ws,
-        separated(1.., parse_send_expression, (ws, "+", ws)),
-        _: ws, _: ";"
+        cut_err(separated(1.., parse_send_expression, (ws, "+", ws)))
+            .context(expected("a key or `String(...)` after `Send:`")),
+        _: terminator("`;` after Send statement")
     )
     .map(|(exprs,)| Statement::Send(exprs))
     .parse_next(input)
 }
 
-fn parse_send_expression(input: &mut &str) -> PResult<SendExpression> {
-    alt((parse_string_literal_expr, parse_key_expr)).parse_next(input)
+fn parse_send_expression(input: &mut Input<'_>) -> PResult<Spanned<SendExpression>> {
+    spanned(alt((parse_string_literal_expr, parse_key_expr))).parse_next(input)
 }
 
-fn parse_string_literal_expr(input: &mut &str) -> PResult<SendExpression> {
+fn parse_string_literal_expr(input: &mut Input<'_>) -> PResult<SendExpression> {
     seq!(
         _: "String", _: ws, _: "(", _: ws,
-        parse_string_literal,
-        _: ws, _: ")"
+        cut_err(parse_string_literal).context(expected("string literal inside `String(...)`")),
+        _: ws, _: cut_err(")").context(expected("`)` to close `String(`"))
     )
     .map(|(s,)| SendExpression::String(s))
     .parse_next(input)
 }
 
-fn parse_key_expr(input: &mut &str) -> PResult<SendExpression> {
+fn parse_key_expr(input: &mut Input<'_>) -> PResult<SendExpression> {
     let key = parse_trigger_key.parse_next(input)?;
     let suffix: Option<&str> = opt(alt((":hold", ":release"))).parse_next(input)?;
     match suffix {
@@ -250,31 +449,32 @@ fn parse_key_expr(input: &mut &str) -> PResult<SendExpression> {
     }
 }
 
-fn parse_wait_stmt(input: &mut &str) -> PResult<Statement> {
+fn parse_wait_stmt(input: &mut Input<'_>) -> PResult<Statement> {
     seq!(
         _: "wait", _: ws, _: "(", _: ws,
-        digit1,
-        _: ws, _: ")", _: ws, _: ";"
+        cut_err(digit1).context(expected("milliseconds inside `wait(...)`")),
+        _: ws, _: cut_err(")").context(expected("`)` to close `wait(`")),
+        _: terminator("`;` after wait statement")
     )
     .map(|(val,): (&str,)| Statement::Wait(val.parse::<u64>().unwrap_or(0)))
     .parse_next(input)
 }
 
-fn parse_if(input: &mut &str) -> PResult<Statement> {
+fn parse_if(input: &mut Input<'_>) -> PResult<Statement> {
     seq!(
         _: "if", _: multispace1,
-        parse_condition,
+        cut_err(parse_condition),
         _: ws,
-        parse_body_block,
+        cut_err(parse_body_block),
         _: ws,
         repeat(0.., seq!(
             _: "elif", _: multispace1,
-            parse_condition,
+            cut_err(parse_condition),
             _: ws,
-            parse_body_block
+            cut_err(parse_body_block)
         )),
         _: ws,
-        opt(preceded(("else", ws), parse_body_block))
+        opt(preceded(("else", ws), cut_err(parse_body_block)))
     )
     .map(|(cond, then_b, elif_bs, else_b)| Statement::If {
         condition: cond,
@@ -285,31 +485,160 @@ fn parse_if(input: &mut &str) -> PResult<Statement> {
     .parse_next(input)
 }
 
-fn parse_loop(input: &mut &str) -> PResult<Statement> {
+fn parse_match(input: &mut Input<'_>) -> PResult<Statement> {
+    seq!(
+        _: keyword("match"), _: ws,
+        cut_err(spanned(parse_condition_call)),
+        _: ws,
+        _: cut_err("{").context(expected("`{` to open the match arms")),
+        _: ws,
+        repeat(0.., terminated(parse_match_arm, ws)),
+        _: cut_err("}").context(expected("a match arm such as `Code_A => { ... }` or `}`"))
+    )
+    .map(|(condition, arms)| Statement::Match { condition, arms })
+    .parse_next(input)
+}
+
+fn parse_match_arm(input: &mut Input<'_>) -> PResult<MatchArm> {
     seq!(
-        _: "loop", _: multispace1,
-        digit1,
+        spanned(alt((
+            keyword("timeout").value(MatchPattern::Timeout),
+            parse_trigger_combinations.map(MatchPattern::Keys),
+        ))),
         _: ws,
-        parse_body_block
+        _: cut_err("=>").context(expected("`=>` after the match pattern")),
+        _: ws,
+        cut_err(parse_body_block)
+    )
+    .map(|(pattern, body)| MatchArm { pattern, body })
+    .parse_next(input)
+}
+
+// `loop 10 { ... }` or `loop { ... }`
+fn parse_loop(input: &mut Input<'_>) -> PResult<Statement> {
+    let count: Option<&str> = preceded(
+        (keyword("loop"), ws),
+        alt((terminated(digit1, ws).map(Some), peek("{").value(None))),
     )
-    .map(|(count, body): (&str, Vec<Statement>)| Statement::Loop {
-        count: count.parse().unwrap_or(1),
+    .parse_next(input)?;
+    let body = cut_err(parse_body_block).parse_next(input)?;
+    Ok(Statement::Loop {
+        count: count.map(|c| c.parse().unwrap_or(1)),
         body,
     })
+}
+
+fn parse_while(input: &mut Input<'_>) -> PResult<Statement> {
+    seq!(
+        _: keyword("while"), _: ws,
+        cut_err(parse_condition),
+        _: ws,
+        cut_err(parse_body_block)
+    )
+    .map(|(condition, body)| Statement::While { condition, body })
     .parse_next(input)
 }
 
-fn parse_macro_call(input: &mut &str) -> PResult<Statement> {
+fn parse_break(input: &mut Input<'_>) -> PResult<Statement> {
+    (keyword("break"), terminator("`;` after break"))
+        .value(Statement::Break)
+        .parse_next(input)
+}
+
+fn parse_continue(input: &mut Input<'_>) -> PResult<Statement> {
+    (keyword("continue"), terminator("`;` after continue"))
+        .value(Statement::Continue)
+        .parse_next(input)
+}
+
+// NAME!; or NAME!("a", "b");
+fn parse_macro_call(input: &mut Input<'_>) -> PResult<Statement> {
     seq!(
         parse_identifier,
-        _: "!", _: ws, _: ";"
+        _: "!",
+        opt(parse_macro_args).map(Option::unwrap_or_default),
+        _: terminator("`;` after macro call")
+    )
+    .map(|(name, args)| Statement::MacroCall { name, args })
+    .parse_next(input)
+}
+
+fn parse_macro_args(input: &mut Input<'_>) -> PResult<Vec<String>> {
+    delimited(
+        ("(", ws),
+        separated(0.., parse_string_literal, (ws, ",", ws)),
+        (
+            ws,
+            cut_err(")").context(expected("string literal argument or `)`")),
+        ),
     )
-    .map(|(name,)| Statement::MacroCall(name))
     .parse_next(input)
 }
 
 // Conditions
-fn parse_condition(input: &mut &str) -> PResult<Condition> {
+// Precedence from loosest to tightest: `or`, `and`, `not`, parentheses
+fn parse_condition(input: &mut Input<'_>) -> PResult<Spanned<Condition>> {
+    parse_or_condition(input)
+}
+
+fn parse_or_condition(input: &mut Input<'_>) -> PResult<Spanned<Condition>> {
+    let mut lhs = parse_and_condition(input)?;
+    while let Some(rhs) = opt(preceded(
+        (ws, keyword("or"), ws),
+        cut_err(parse_and_condition),
+    ))
+    .parse_next(input)?
+    {
+        let span = lhs.span.merge(rhs.span);
+        lhs = Spanned::new(Condition::Or(Box::new(lhs), Box::new(rhs)), span);
+    }
+    Ok(lhs)
+}
+
+fn parse_and_condition(input: &mut Input<'_>) -> PResult<Spanned<Condition>> {
+    let mut lhs = parse_unary_condition(input)?;
+    while let Some(rhs) = opt(preceded(
+        (ws, keyword("and"), ws),
+        cut_err(parse_unary_condition),
+    ))
+    .parse_next(input)?
+    {
+        let span = lhs.span.merge(rhs.span);
+        lhs = Spanned::new(Condition::And(Box::new(lhs), Box::new(rhs)), span);
+    }
+    Ok(lhs)
+}
+
+fn parse_unary_condition(input: &mut Input<'_>) -> PResult<Spanned<Condition>> {
+    alt((
+        spanned(
+            preceded((keyword("not"), ws), cut_err(parse_unary_condition))
+                .map(|inner| Condition::Not(Box::new(inner))),
+        ),
+        preceded(
+            ("(", ws),
+            cut_err(terminated(
+                parse_condition,
+                (
+                    ws,
+                    cut_err(")").context(expected("`)` to close the condition")),
+                ),
+            )),
+        ),
+        spanned(parse_condition_call),
+    ))
+    .parse_next(input)
+}
+
+// A word that is not the prefix of a longer identifier
+fn keyword<'a>(word: &'static str) -> impl Parser<Input<'a>, &'a str, ErrMode<ContextError>> {
+    terminated(
+        word,
+        not(take_while(1, |c: char| c.is_alphanumeric() || c == '_')),
+    )
+}
+
+fn parse_condition_call(input: &mut Input<'_>) -> PResult<Condition> {
     alt((
         parse_wait_input_time,
         parse_wait_input,
@@ -317,26 +646,28 @@ fn parse_condition(input: &mut &str) -> PResult<Condition> {
         parse_wait_released_time,
         parse_wait_released,
     ))
+    .context(expected(
+        "a condition such as `wait_input(...)` or `now_input(...)`",
+    ))
     .parse_next(input)
 }
 
-fn parse_wait_input(input: &mut &str) -> PResult<Condition> {
+fn parse_wait_input(input: &mut Input<'_>) -> PResult<Condition> {
     seq!(
         _: "wait_input", _: ws, _: "(", _: ws,
-        parse_condition_args,
-        _: ws, _: ")"
+        cut_err(parse_condition_args).context(expected("trigger keys inside `wait_input(...)`")),
+        _: ws, _: cut_err(")").context(expected("`)` to close `wait_input(`"))
     )
     .map(|(args,)| Condition::WaitInput(args))
     .parse_next(input)
 }
 
-fn parse_wait_input_time(input: &mut &str) -> PResult<Condition> {
+fn parse_wait_input_time(input: &mut Input<'_>) -> PResult<Condition> {
     seq!(
         _: "wait_input_time", _: ws, _: "(", _: ws,
-        parse_condition_args,
-        _: ws, _: ",", _: ws,
-        digit1,
-        _: ws, _: ")"
+        cut_err(parse_timed_condition_args).context(expected("trigger keys and `,` inside `wait_input_time(...)`")),
+        cut_err(digit1).context(expected("timeout in milliseconds")),
+        _: ws, _: cut_err(")").context(expected("`)` to close `wait_input_time(`"))
     )
     .map(|(args, time): (Vec<TriggerCombinations>, &str)| {
         Condition::WaitInputTime(args, time.parse().unwrap_or(0))
@@ -344,33 +675,32 @@ fn parse_wait_input_time(input: &mut &str) -> PResult<Condition> {
     .parse_next(input)
 }
 
-fn parse_now_input(input: &mut &str) -> PResult<Condition> {
+fn parse_now_input(input: &mut Input<'_>) -> PResult<Condition> {
     seq!(
         _: "now_input", _: ws, _: "(", _: ws,
-        parse_condition_args,
-        _: ws, _: ")"
+        cut_err(parse_condition_args).context(expected("trigger keys inside `now_input(...)`")),
+        _: ws, _: cut_err(")").context(expected("`)` to close `now_input(`"))
     )
     .map(|(args,)| Condition::NowInput(args))
     .parse_next(input)
 }
 
-fn parse_wait_released(input: &mut &str) -> PResult<Condition> {
+fn parse_wait_released(input: &mut Input<'_>) -> PResult<Condition> {
     seq!(
         _: "wait_released", _: ws, _: "(", _: ws,
-        parse_condition_args,
-        _: ws, _: ")"
+        cut_err(parse_condition_args).context(expected("trigger keys inside `wait_released(...)`")),
+        _: ws, _: cut_err(")").context(expected("`)` to close `wait_released(`"))
     )
     .map(|(args,)| Condition::WaitReleased(args))
     .parse_next(input)
 }
 
-fn parse_wait_released_time(input: &mut &str) -> PResult<Condition> {
+fn parse_wait_released_time(input: &mut Input<'_>) -> PResult<Condition> {
     seq!(
         _: "wait_released_time", _: ws, _: "(", _: ws,
-        parse_condition_args,
-        _: ws, _: ",", _: ws,
-        digit1,
-        _: ws, _: ")"
+        cut_err(parse_timed_condition_args).context(expected("trigger keys and `,` inside `wait_released_time(...)`")),
+        cut_err(digit1).context(expected("timeout in milliseconds")),
+        _: ws, _: cut_err(")").context(expected("`)` to close `wait_released_time(`"))
     )
     .map(|(args, time): (Vec<TriggerCombinations>, &str)| {
         Condition::WaitReleasedTime(args, time.parse().unwrap_or(0))
@@ -378,16 +708,81 @@ fn parse_wait_released_time(input: &mut &str) -> PResult<Condition> {
     .parse_next(input)
 }
 
-fn parse_condition_args(input: &mut &str) -> PResult<Vec<TriggerCombinations>> {
-    let combo = parse_trigger_combinations(input)?;
-    Ok(vec![combo])
+// Alternatives: `Code_Y, Code_N, Code_Shift + Code_Q`
+fn parse_condition_args(input: &mut Input<'_>) -> PResult<Vec<TriggerCombinations>> {
+    separated(1.., parse_trigger_combinations, (ws, ",", ws)).parse_next(input)
+}
+
+// Alternatives, each followed by a `,`. The timeout also parses as a plain key
+// name, but isn't followed by a `,`, so it is left for the caller.
+fn parse_timed_condition_args(input: &mut Input<'_>) -> PResult<Vec<TriggerCombinations>> {
+    repeat(1.., terminated(parse_trigger_combinations, (ws, ",", ws))).parse_next(input)
 }
 
 // Utilities
-fn parse_string_literal(input: &mut &str) -> PResult<String> {
-    delimited('"', take_while(0.., |c| c != '"'), '"')
-        .map(|s: &str| s.to_string())
-        .parse_next(input)
+fn parse_string_literal(input: &mut Input<'_>) -> PResult<String> {
+    alt((parse_raw_string, parse_escaped_string)).parse_next(input)
+}
+
+// "..." with backslash escapes
+fn parse_escaped_string(input: &mut Input<'_>) -> PResult<String> {
+    let start = input.checkpoint();
+    '"'.parse_next(input)?;
+    let mut value = String::new();
+    loop {
+        let chunk: &str = take_till(0.., ['"', '\\']).parse_next(input)?;
+        value.push_str(chunk);
+        match opt(any).parse_next(input)? {
+            Some('"') => return Ok(value),
+            Some(_) => value.push(cut_err(parse_escape).parse_next(input)?),
+            // Ran into the end of input: point at the string left open
+            None => {
+                input.reset(&start);
+                let mut err = ContextError::new();
+                err.push(expected("closing `\"` for this string"));
+                return Err(ErrMode::Cut(err));
+            }
+        }
+    }
+}
+
+// The character after a backslash
+fn parse_escape(input: &mut Input<'_>) -> PResult<char> {
+    alt((
+        '"'.value('"'),
+        '\\'.value('\\'),
+        'n'.value('\n'),
+        't'.value('\t'),
+        'r'.value('\r'),
+        parse_unicode_escape,
+    ))
+    .context(expected(
+        "escape sequence such as `\\n`, `\\\"` or `\\u{...}`",
+    ))
+    .parse_next(input)
+}
+
+fn parse_unicode_escape(input: &mut Input<'_>) -> PResult<char> {
+    preceded(
+        "u{",
+        cut_err(terminated(take_while(1..=6, AsChar::is_hex_digit), '}'))
+            .verify_map(|hex: &str| u32::from_str_radix(hex, 16).ok().and_then(char::from_u32))
+            .context(expected("unicode scalar value inside `\\u{...}`")),
+    )
+    .parse_next(input)
+}
+
+// r"..." or r#"..."#, taken verbatim (e.g. Windows paths)
+fn parse_raw_string(input: &mut Input<'_>) -> PResult<String> {
+    let hashes: &str = delimited('r', take_while(0.., '#'), '"').parse_next(input)?;
+    let closing = format!("\"{}", hashes);
+    cut_err(terminated(
+        take_until(0.., closing.as_str()),
+        closing.as_str(),
+    ))
+    .context(expected("closing delimiter of raw string"))
+    .map(|s: &str| s.to_string())
+    .parse_next(input)
 }
 
 #[cfg(test)]
@@ -441,13 +836,66 @@ mod tests {
     fn test_parse_backslash_in_string() {
         let mut input = r#"
             macro TEST {
-                Run: "C:\path\to\app.exe";
+                Run: r"C:\path\to\app.exe";
             }
         "#;
         let script = parse_script
             .parse_next(&mut input)
             .expect("Should handle backslashes in strings");
         assert_eq!(script.macros.len(), 1);
+        assert_eq!(
+            script.macros[0].body[0].node,
+            Statement::Run(r"C:\path\to\app.exe".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_raw_string_with_hashes() {
+        let mut input = r##"
+            macro TEST {
+                Execute: r#"C:\Program Files\"quoted"\app.exe"#;
+            }
+        "##;
+        let script = parse_script
+            .parse_next(&mut input)
+            .expect("Should parse raw string with hashes");
+        assert_eq!(
+            script.macros[0].body[0].node,
+            Statement::Execute(r#"C:\Program Files\"quoted"\app.exe"#.to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_string_escapes() {
+        let mut input = r#"
+            Code_F1 {
+                Run: "echo \"hi\" \\ done";
+                Send: String("a\tb\nc\u{3042}");
+            }
+        "#;
+        let script = parse_script
+            .parse_next(&mut input)
+            .expect("Should parse escape sequences");
+        let body = &script.blocks[0].body;
+        assert_eq!(
+            body[0].node,
+            Statement::Run(r#"echo "hi" \ done"#.to_string())
+        );
+        let Statement::Send(exprs) = &body[1].node else {
+            panic!("Expected Statement::Send, got {:?}", body[1]);
+        };
+        assert_eq!(
+            exprs[0].node,
+            SendExpression::String("a\tb\nc\u{3042}".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_error_unknown_escape() {
+        let source = "Code_A {\n    Run: \"C:\\path\";\n}";
+        let err = parse_source(source, "d.phybkc").expect_err("Should reject `\\p`");
+        assert!(err.message.starts_with("expected escape sequence"));
+        assert_eq!((err.line, err.column), (2, 14));
     }
 
     #[test]
@@ -459,7 +907,7 @@ mod tests {
             .parse_next(&mut input)
             .expect("Should handle unquoted CLI");
         assert_eq!(script.global_settings.len(), 1);
-        let GlobalSetting::Cli(val) = &script.global_settings[0];
+        let GlobalSetting::Cli(val) = &script.global_settings[0].node;
         assert_eq!(val, "PowerShell");
     }
 
@@ -473,15 +921,15 @@ mod tests {
         let script = parse_script
             .parse_next(&mut input)
             .expect("Should parse script with send combo");
-        let stmt = &script.blocks[0].body[0];
+        let stmt = &script.blocks[0].body[0].node;
         if let Statement::Send(exprs) = stmt {
             assert_eq!(exprs.len(), 2, "Should be 2 expressions (Hold and Key)");
-            if let SendExpression::Hold(key) = &exprs[0] {
+            if let SendExpression::Hold(key) = &exprs[0].node {
                 assert_eq!(key, &TriggerKey::Virtual("Ctrl".to_string()));
             } else {
                 panic!("Expected SendExpression::Hold, got {:?}", exprs[0]);
             }
-            if let SendExpression::Key(key) = &exprs[1] {
+            if let SendExpression::Key(key) = &exprs[1].node {
                 assert_eq!(key, &TriggerKey::Virtual("X".to_string()));
             } else {
                 panic!("Expected SendExpression::Key, got {:?}", exprs[1]);
@@ -490,4 +938,282 @@ mod tests {
             panic!("Expected Statement::Send, got {:?}", stmt);
         }
     }
+
+    #[test]
+    fn test_parse_error_missing_semicolon() {
+        let source = "macro A {\n    Run: \"echo\"\n    wait(10);\n}\n";
+        let err = parse_source(source, "a.phybkc").expect_err("Should fail without `;`");
+        assert_eq!(err.message, "expected `;` after Run statement");
+        assert_eq!((err.line, err.column), (2, 16));
+        assert_eq!(err.source_line, "    Run: \"echo\"");
+    }
+
+    #[test]
+    fn test_parse_error_display() {
+        let source = "Code_A {\n    Rn: \"x\";\n}";
+        let err = parse_source(source, "b.phybkc").expect_err("Should fail on unknown statement");
+        let expected = "error: expected a statement or `}`\n \
+                        --> b.phybkc:2:5\n  \
+                        |\n\
+                        2 |     Rn: \"x\";\n  \
+                        |     ^";
+        assert_eq!(err.to_string(), expected);
+    }
+
+    #[test]
+    fn test_parse_error_unterminated_string() {
+        let source = "Code_A {\n    Send: String(\"abc);\n}";
+        let err = parse_source(source, "c.phybkc").expect_err("Should fail on open string");
+        assert_eq!(err.message, "expected closing `\"` for this string");
+        // At the opening quote, not the end of input
+        assert_eq!((err.line, err.column), (2, 18));
+    }
+
+    #[test]
+    fn test_parse_spans() {
+        let source = "macro A {\n    Run: \"x\";\n}\n\nCode_Ctrl + #0x1E {\n    if now_input(Code_B) {\n        Send: Code_C;\n    }\n}\n";
+        let script = parse_source(source, "spans.phybkc").unwrap();
+
+        let run = &script.macros[0].body[0];
+        assert_eq!((run.span.line, run.span.column), (2, 5));
+        assert_eq!(&source[run.span.start..run.span.end], "Run: \"x\";");
+
+        let block = &script.blocks[0];
+        assert_eq!((block.span.line, block.span.column), (5, 1));
+        let Trigger::Keys(triggers) = &block.trigger else {
+            panic!("Expected key triggers, got {:?}", block.trigger);
+        };
+        let key = &triggers[0].0[1];
+        assert_eq!(key.node, TriggerKey::Physical(0x1E));
+        assert_eq!((key.span.line, key.span.column), (5, 13));
+
+        let Statement::If {
+            condition,
+            then_branch,
+            ..
+        } = &block.body[0].node
+        else {
+            panic!("Expected Statement::If, got {:?}", block.body[0]);
+        };
+        assert_eq!(
+            &source[condition.span.start..condition.span.end],
+            "now_input(Code_B)"
+        );
+        let Statement::Send(exprs) = &then_branch[0].node else {
+            panic!("Expected Statement::Send, got {:?}", then_branch[0]);
+        };
+        assert_eq!((exprs[0].span.line, exprs[0].span.column), (7, 15));
+    }
+
+    #[test]
+    fn test_parse_items_in_any_order() {
+        let mut input = r#"
+            Code_F1 {
+                HELPER!;
+            }
+            macro HELPER {
+                Run: "echo helper";
+            }
+            CLI = PowerShell;
+            Code_F2 {
+                Run: "echo f2";
+            }
+            macro OTHER {
+                wait(10);
+            }
+        "#;
+        let script = parse_script
+            .parse_next(&mut input)
+            .expect("Should parse interleaved top-level items");
+        assert_eq!(script.global_settings.len(), 1);
+        let names: Vec<&str> = script.macros.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["HELPER", "OTHER"]);
+        assert_eq!(script.blocks.len(), 2);
+        assert_eq!(script.blocks[1].span.line, 9);
+    }
+
+    #[test]
+    fn test_parse_condition_precedence() {
+        let mut input = r#"
+            Code_F1 {
+                if now_input(Code_A) or not now_input(Code_B) and (now_input(Code_C) or now_input(Code_D)) {
+                    wait(1);
+                }
+            }
+        "#;
+        let script = parse_script
+            .parse_next(&mut input)
+            .expect("Should parse boolean conditions");
+        let Statement::If { condition, .. } = &script.blocks[0].body[0].node else {
+            panic!("Expected Statement::If");
+        };
+        // a or ((not b) and (c or d))
+        let Condition::Or(lhs, rhs) = &condition.node else {
+            panic!("Expected `or` at the root, got {:?}", condition.node);
+        };
+        assert!(matches!(lhs.node, Condition::NowInput(_)));
+        let Condition::And(not_b, c_or_d) = &rhs.node else {
+            panic!("Expected `and`, got {:?}", rhs.node);
+        };
+        assert!(matches!(not_b.node, Condition::Not(_)));
+        assert!(matches!(c_or_d.node, Condition::Or(_, _)));
+        assert_eq!(condition.span.column, 20);
+        assert_eq!(input, "");
+    }
+
+    #[test]
+    fn test_parse_loops() {
+        let mut input = r#"
+            Code_F1 {
+                loop 3 { wait(1); }
+                loop {
+                    continue;
+                }
+                while now_input(Code_A) and not now_input(Code_B) {
+                    break;
+                }
+            }
+        "#;
+        let script = parse_script
+            .parse_next(&mut input)
+            .expect("Should parse loop statements");
+        let body = &script.blocks[0].body;
+        assert!(matches!(
+            body[0].node,
+            Statement::Loop { count: Some(3), .. }
+        ));
+        let Statement::Loop {
+            count: None,
+            body: inner,
+        } = &body[1].node
+        else {
+            panic!("Expected unbounded loop, got {:?}", body[1].node);
+        };
+        assert_eq!(inner[0].node, Statement::Continue);
+        let Statement::While {
+            condition,
+            body: inner,
+        } = &body[2].node
+        else {
+            panic!("Expected Statement::While, got {:?}", body[2].node);
+        };
+        assert!(matches!(condition.node, Condition::And(_, _)));
+        assert_eq!(inner[0].node, Statement::Break);
+        assert_eq!(input, "");
+    }
+
+    #[test]
+    fn test_parse_macro_params() {
+        let mut input = r#"
+            macro OpenIn(app, file) {
+                Execute: "${app} ${file}";
+            }
+            Code_F1 {
+                OpenIn!("code", r"C:\notes.md");
+                NoArgs!();
+            }
+        "#;
+        let script = parse_script
+            .parse_next(&mut input)
+            .expect("Should parse macro parameters and arguments");
+        assert_eq!(script.macros[0].params, ["app", "file"]);
+        assert_eq!(
+            script.blocks[0].body[0].node,
+            Statement::MacroCall {
+                name: "OpenIn".to_string(),
+                args: vec!["code".to_string(), r"C:\notes.md".to_string()],
+            }
+        );
+        assert_eq!(
+            script.blocks[0].body[1].node,
+            Statement::MacroCall {
+                name: "NoArgs".to_string(),
+                args: vec![],
+            }
+        );
+        assert_eq!(input, "");
+    }
+
+    #[test]
+    fn test_parse_sequence_triggers() {
+        let mut input = r#"
+            Code_Space -> Code_F -> Code_Ctrl + Code_O {
+                wait(1);
+            }
+            Code_G, Code_G {
+                wait(1);
+            }
+        "#;
+        let script = parse_script
+            .parse_next(&mut input)
+            .expect("Should parse sequence triggers");
+        let Trigger::Keys(steps) = &script.blocks[0].trigger else {
+            panic!("Expected key triggers");
+        };
+        let steps: Vec<usize> = steps.iter().map(|combo| combo.0.len()).collect();
+        assert_eq!(steps, [1, 1, 2]);
+        assert!(matches!(&script.blocks[1].trigger, Trigger::Keys(steps) if steps.len() == 2));
+    }
+
+    #[test]
+    fn test_parse_chord_trigger() {
+        let script = parse_source(
+            "chord(Code_J, #0x25, 50) {
+    Send: Code_Escape;
+}
+chord {
+    wait(1);
+}",
+            "chord.phybkc",
+        )
+        .expect("Should parse a chord");
+        let Trigger::Chord(keys, 50) = &script.blocks[0].trigger else {
+            panic!("Expected a 50ms chord, got {:?}", script.blocks[0].trigger);
+        };
+        assert_eq!(keys[1].node, TriggerKey::Physical(0x25));
+        // Without `(` it is just a key named `chord`
+        assert!(matches!(script.blocks[1].trigger, Trigger::Keys(_)));
+
+        let err = parse_source("chord(Code_J, 50) {}", "c.phybkc").expect_err("One key");
+        assert_eq!(err.message, "a chord needs at least two keys");
+        let err = parse_source("chord(Code_J, Code_J, 50) {}", "c.phybkc").expect_err("Duplicate");
+        assert_eq!(err.message, "duplicate key in chord");
+    }
+
+    #[test]
+    fn test_parse_condition_alternatives() {
+        let mut input = r#"
+            Code_F1 {
+                if wait_input_time(Code_Y, Code_N, Code_Shift + Code_Q, 500) {
+                    wait(1);
+                }
+                match wait_input(Code_Y, #0x31) {
+                    Code_Y => { wait(1); }
+                    timeout => {}
+                }
+            }
+        "#;
+        let script = parse_script
+            .parse_next(&mut input)
+            .expect("Should parse condition alternatives");
+        let Statement::If { condition, .. } = &script.blocks[0].body[0].node else {
+            panic!("Expected Statement::If");
+        };
+        let Condition::WaitInputTime(alternatives, 500) = &condition.node else {
+            panic!(
+                "Expected wait_input_time with 500ms, got {:?}",
+                condition.node
+            );
+        };
+        let lengths: Vec<usize> = alternatives.iter().map(|combo| combo.0.len()).collect();
+        assert_eq!(lengths, [1, 1, 2]);
+
+        let Statement::Match { condition, arms } = &script.blocks[0].body[1].node else {
+            panic!("Expected Statement::Match");
+        };
+        assert_eq!(condition.alternatives().len(), 2);
+        assert!(matches!(arms[0].pattern.node, MatchPattern::Keys(_)));
+        assert_eq!(arms[1].pattern.node, MatchPattern::Timeout);
+        assert_eq!(input, "");
+    }
 }
@@ -0,0 +1,77 @@
+use serde::{Deserialize, Serialize};
+use std::ops::{Deref, Range};
+
+/// Location of a node in its `.phybkc` source.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Span {
+    /// Byte offset of the first character.
+    pub start: usize,
+    /// Byte offset one past the last character.
+    pub end: usize,
+    /// 1-based line of `start`.
+    pub line: usize,
+    /// 1-based column of `start`, counted in characters.
+    pub column: usize,
+}
+
+impl Span {
+    /// The span covering both `self` and a later `other`.
+    pub fn merge(self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..self
+        }
+    }
+}
+
+/// A node together with the span it was parsed from.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Self { node, span }
+    }
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
+
+/// Line start offsets of a source, used to turn byte ranges into spans.
+#[derive(Debug)]
+pub(crate) struct LineIndex<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub(crate) fn new(source: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            source,
+            line_starts,
+        }
+    }
+
+    pub(crate) fn span(&self, range: Range<usize>) -> Span {
+        let line = self.line_starts.partition_point(|&s| s <= range.start);
+        let line_start = self.line_starts[line - 1];
+        let column = self.source[line_start..range.start].chars().count() + 1;
+        Span {
+            start: range.start,
+            end: range.end,
+            line,
+            column,
+        }
+    }
+}
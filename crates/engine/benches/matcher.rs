@@ -0,0 +1,35 @@
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use engine::TriggerMatcher;
+use std::hint::black_box;
+
+// `count` distinct bindings of one to three keys over the main key block
+fn bindings(count: usize) -> Vec<(Vec<u16>, usize)> {
+    let keys: Vec<u16> = (0x02..=0x35).collect();
+    (0..count)
+        .map(|i| {
+            let last = keys[i % keys.len()];
+            let combo = match i / keys.len() {
+                0 => vec![last],
+                n if n <= keys.len() => vec![keys[n - 1], last],
+                n => vec![0x1D, keys[(n - 1) % keys.len()], last],
+            };
+            (combo, i)
+        })
+        .collect()
+}
+
+fn bench_find(c: &mut Criterion) {
+    let mut group = c.benchmark_group("TriggerMatcher::find");
+    for count in [10, 100, 1000, 2500] {
+        let matcher = TriggerMatcher::new(bindings(count), vec![0x3A]);
+        // Ctrl + S + A with Caps Lock held: a typical miss that still walks the trie
+        let held = [0x3A, 0x1D, 0x1F, 0x1E];
+        group.bench_with_input(BenchmarkId::from_parameter(count), &held, |b, held| {
+            b.iter(|| matcher.find(black_box(held), black_box(0x1E)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_find);
+criterion_main!(benches);
@@ -0,0 +1,116 @@
+use crate::BlockId;
+use profile::expand_modifiers;
+use std::time::Duration;
+
+/// Chord triggers (`chord(Code_J, Code_K, 50)`): keys that go down in any
+/// order within a time window.
+#[derive(Debug, Default)]
+pub(crate) struct ChordMatcher {
+    chords: Vec<Chord>,
+    /// Every key of every chord, sorted
+    keys: Vec<u16>,
+}
+
+#[derive(Debug)]
+struct Chord {
+    /// Sorted
+    keys: Vec<u16>,
+    window: Duration,
+    block: BlockId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChordProgress {
+    /// The keys pressed so far are exactly a chord
+    Complete(BlockId),
+    /// They are part of a chord whose window is still open; the longest such
+    /// window
+    Partial(Duration),
+}
+
+impl ChordMatcher {
+    /// Generic modifiers are expanded to both sides.
+    pub(crate) fn new(chords: impl IntoIterator<Item = (Vec<u16>, Duration, BlockId)>) -> Self {
+        let chords: Vec<Chord> = chords
+            .into_iter()
+            .flat_map(|(keys, window, block)| {
+                expand_modifiers(&keys).into_iter().map(move |mut keys| {
+                    keys.sort_unstable();
+                    Chord {
+                        keys,
+                        window,
+                        block,
+                    }
+                })
+            })
+            .filter(|chord| chord.keys.len() >= 2)
+            .collect();
+        let mut keys: Vec<u16> = chords.iter().flat_map(|c| c.keys.clone()).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        Self { chords, keys }
+    }
+
+    /// Whether `key` is part of any chord.
+    pub(crate) fn contains(&self, key: u16) -> bool {
+        self.keys.binary_search(&key).is_ok()
+    }
+
+    /// How far `pressed`, the chord keys pressed `elapsed` after the first of
+    /// them, gets towards a chord. Ties go to the lower block id.
+    pub(crate) fn progress(&self, pressed: &[u16], elapsed: Duration) -> Option<ChordProgress> {
+        let candidates = self
+            .chords
+            .iter()
+            .filter(|c| elapsed <= c.window && pressed.iter().all(|k| c.keys.contains(k)));
+        let mut longest = None;
+        let mut complete = None;
+        for chord in candidates {
+            if chord.keys.len() == pressed.len() {
+                complete = Some(complete.map_or(chord.block, |b: BlockId| b.min(chord.block)));
+            }
+            longest = longest.max(Some(chord.window));
+        }
+        match complete {
+            Some(block) => Some(ChordProgress::Complete(block)),
+            None => longest.map(ChordProgress::Partial),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const J: u16 = 0x24;
+    const K: u16 = 0x25;
+    const L: u16 = 0x26;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_progress() {
+        let m = ChordMatcher::new([(vec![J, K], ms(50), 0), (vec![J, K, L], ms(80), 1)]);
+        assert!(m.contains(L));
+        assert_eq!(
+            m.progress(&[K], ms(0)),
+            Some(ChordProgress::Partial(ms(80)))
+        );
+        assert_eq!(
+            m.progress(&[K, J], ms(30)),
+            Some(ChordProgress::Complete(0))
+        );
+        // Too late for J+K, still in time for J+K+L
+        assert_eq!(
+            m.progress(&[K, J], ms(60)),
+            Some(ChordProgress::Partial(ms(80)))
+        );
+        assert_eq!(
+            m.progress(&[L, J, K], ms(70)),
+            Some(ChordProgress::Complete(1))
+        );
+        assert_eq!(m.progress(&[J, 0x1E], ms(0)), None);
+    }
+}
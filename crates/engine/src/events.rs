@@ -0,0 +1,150 @@
+use crate::wait::{KeyWait, WaitState};
+use crate::{HeldKeys, KeyEvent};
+use std::collections::BTreeSet;
+use tokio::sync::broadcast::{self, Receiver, error::RecvError};
+use tokio::time::timeout_at;
+
+/// The key events seen by the hook, broadcast to the script tasks waiting
+/// on them. Cloning gives another handle to the same channel.
+#[derive(Debug, Clone)]
+pub struct KeyEvents(broadcast::Sender<KeyEvent>);
+
+impl Default for KeyEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyEvents {
+    /// Events a subscriber can fall behind by before it sees
+    /// `RecvError::Lagged`.
+    pub const CAPACITY: usize = 1024;
+
+    pub fn new() -> Self {
+        Self(broadcast::channel(Self::CAPACITY).0)
+    }
+
+    /// Publish to every subscriber. Never blocks: a subscriber that falls
+    /// more than `CAPACITY` events behind loses the oldest ones instead.
+    pub fn publish(&self, event: KeyEvent) {
+        // Fails only when nobody is subscribed
+        let _ = self.0.send(event);
+    }
+
+    /// A receiver of the events published from now on.
+    pub fn subscribe(&self) -> Receiver<KeyEvent> {
+        self.0.subscribe()
+    }
+
+    /// Feed `wait` the events published from now on until it ends, starting
+    /// from the keys in `held`. Never returns `WaitState::Waiting`.
+    ///
+    /// When the wait falls behind by more than `CAPACITY` events
+    /// (`RecvError::Lagged`), the missed events are dropped and it restarts
+    /// from the keys held at that point, see [`KeyWait::restart`].
+    pub async fn wait(&self, held: &HeldKeys, mut wait: KeyWait) -> WaitState {
+        let (mut events, keys) = self.subscribe_held(held);
+        let mut state = wait.start(keys);
+        while state == WaitState::Waiting {
+            let received = match wait.deadline() {
+                Some(deadline) => match timeout_at(deadline.into(), events.recv()).await {
+                    Ok(received) => received,
+                    Err(_) => return WaitState::TimedOut,
+                },
+                None => events.recv().await,
+            };
+            state = match received {
+                Ok(event) => wait.feed(&event),
+                Err(RecvError::Lagged(_)) => {
+                    let keys;
+                    (events, keys) = self.subscribe_held(held);
+                    wait.restart(keys)
+                }
+                // `self` keeps the sender alive
+                Err(RecvError::Closed) => unreachable!(),
+            };
+        }
+        state
+    }
+
+    // A receiver and the keys held when it started. The hook updates the
+    // held set before publishing, so an event racing with this shows up in
+    // the set (and its echo on the receiver looks like auto-repeat) rather
+    // than being lost.
+    fn subscribe_held(&self, held: &HeldKeys) -> (Receiver<KeyEvent>, BTreeSet<u16>) {
+        let events = self.subscribe();
+        (events, held.snapshot())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WaitFor;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    const A: u16 = 0x1E;
+    const B: u16 = 0x30;
+
+    // Updates `held` before publishing, as the hook does
+    fn press(events: &KeyEvents, held: &HeldKeys, scancode: u16, down: bool) {
+        held.set(scancode, down);
+        events.publish(KeyEvent::new(scancode, down));
+    }
+
+    #[tokio::test]
+    async fn test_publish_and_subscribe() {
+        let events = KeyEvents::new();
+        // Nobody is listening yet
+        events.publish(KeyEvent::new(A, true));
+
+        let mut first = events.subscribe();
+        let mut second = events.subscribe();
+        let event = KeyEvent::new(B, true);
+        events.publish(event);
+        assert_eq!(first.recv().await.unwrap(), event);
+        assert_eq!(second.recv().await.unwrap(), event);
+    }
+
+    #[tokio::test]
+    async fn test_lagged_subscriber_loses_the_oldest_events() {
+        let events = KeyEvents::new();
+        let mut receiver = events.subscribe();
+        for i in 0..KeyEvents::CAPACITY + 10 {
+            events.publish(KeyEvent::new(i as u16, true));
+        }
+        assert!(matches!(receiver.recv().await, Err(RecvError::Lagged(10))));
+        assert_eq!(receiver.recv().await.unwrap().scancode, 10);
+    }
+
+    #[tokio::test]
+    async fn test_wait_restarts_when_lagging() {
+        let events = KeyEvents::new();
+        let held = Arc::new(HeldKeys::new());
+        let wait = KeyWait::new(vec![Some(vec![A, B])], WaitFor::Pressed, None);
+        let task = tokio::spawn({
+            let (events, held) = (events.clone(), Arc::clone(&held));
+            async move { events.wait(&held, wait).await }
+        });
+        // Let the wait subscribe
+        tokio::task::yield_now().await;
+
+        // More repeats of A than the channel holds, then B
+        press(&events, &held, A, true);
+        for _ in 0..KeyEvents::CAPACITY {
+            events.publish(KeyEvent::new(A, true));
+        }
+        press(&events, &held, B, true);
+        assert_eq!(task.await.unwrap(), WaitState::Matched(0));
+    }
+
+    #[tokio::test]
+    async fn test_wait_times_out() {
+        let events = KeyEvents::new();
+        let held = HeldKeys::new();
+        let deadline = Instant::now() + Duration::from_millis(20);
+        let wait = KeyWait::new(vec![Some(vec![A])], WaitFor::Pressed, Some(deadline));
+        assert_eq!(events.wait(&held, wait).await, WaitState::TimedOut);
+    }
+}
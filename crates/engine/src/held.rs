@@ -0,0 +1,77 @@
+use profile::{SCANCODE_SLOTS, index_scancode, scancode_index};
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const WORDS: usize = SCANCODE_SLOTS / 64;
+
+/// Keys currently down as an atomic bitset: the hook thread writes it and
+/// script tasks read it without taking a lock.
+#[derive(Debug)]
+pub struct HeldKeys([AtomicU64; WORDS]);
+
+impl HeldKeys {
+    pub const fn new() -> Self {
+        Self([const { AtomicU64::new(0) }; WORDS])
+    }
+
+    pub fn set(&self, scancode: u16, down: bool) {
+        let Some(index) = scancode_index(scancode) else {
+            return;
+        };
+        let bit = 1u64 << (index % 64);
+        if down {
+            self.0[index / 64].fetch_or(bit, Ordering::Release);
+        } else {
+            self.0[index / 64].fetch_and(!bit, Ordering::Release);
+        }
+    }
+
+    pub fn contains(&self, scancode: u16) -> bool {
+        scancode_index(scancode).is_some_and(|index| {
+            self.0[index / 64].load(Ordering::Acquire) & (1 << (index % 64)) != 0
+        })
+    }
+
+    /// The keys held right now. Words are read one by one, so a key that
+    /// changes during the call may or may not be included.
+    pub fn snapshot(&self) -> BTreeSet<u16> {
+        let mut keys = BTreeSet::new();
+        for (word_index, word) in self.0.iter().enumerate() {
+            let mut bits = word.load(Ordering::Acquire);
+            while bits != 0 {
+                let index = word_index * 64 + bits.trailing_zeros() as usize;
+                keys.insert(index_scancode(index));
+                bits &= bits - 1;
+            }
+        }
+        keys
+    }
+}
+
+impl Default for HeldKeys {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_snapshot() {
+        let held = HeldKeys::new();
+        held.set(0x1E, true);
+        held.set(0xE01D, true);
+        held.set(0x3A, true);
+        held.set(0x3A, false);
+        assert!(held.contains(0x1E));
+        assert!(held.contains(0xE01D));
+        assert!(!held.contains(0x1D));
+        assert!(!held.contains(0x3A));
+        assert_eq!(
+            held.snapshot().into_iter().collect::<Vec<_>>(),
+            [0x1E, 0xE01D]
+        );
+    }
+}
@@ -0,0 +1,1347 @@
+//! Platform-independent key handling: trigger matching and remapping.
+//!
+//! The daemon's keyboard hook feeds every [`KeyEvent`] to an [`Engine`] and
+//! carries out the [`Decision`] it returns. The per-profile [`Bindings`] are
+//! immutable so they can be shared and swapped without locking, while the
+//! `Engine` itself only tracks what is held and lives on the hook thread.
+
+mod chord;
+mod events;
+mod held;
+mod matcher;
+mod sequence;
+mod wait;
+
+use chord::{ChordMatcher, ChordProgress};
+pub use events::KeyEvents;
+pub use held::HeldKeys;
+pub use matcher::TriggerMatcher;
+use profile::{DualRole, KeyAction, Keymap, LayerKey, LayerMode};
+use sequence::{NodeId, SequenceMatcher};
+use std::time::{Duration, Instant};
+pub use wait::{KeyWait, WaitFor, WaitState, all_held};
+
+/// Index of a trigger block in the daemon's block list.
+pub type BlockId = usize;
+
+/// A key transition seen by the hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// Scan code, with 0xE000 set for extended keys
+    pub scancode: u16,
+    pub down: bool,
+    /// When the hook received the event
+    pub timestamp: Instant,
+    /// Generated by `SendInput` (ours or another program's) rather than typed
+    pub injected: bool,
+}
+
+impl KeyEvent {
+    /// A physical event stamped now.
+    pub fn new(scancode: u16, down: bool) -> Self {
+        Self {
+            scancode,
+            down,
+            timestamp: Instant::now(),
+            injected: false,
+        }
+    }
+}
+
+/// What the hook should do with an event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// Let the event through unchanged
+    Pass,
+    /// Drop the event
+    Swallow,
+    /// Drop the event and send this scan code, in the same direction, instead
+    Remap(u16),
+    /// Drop the event and run the trigger block
+    Run(BlockId),
+    /// Drop the event and carry out the action for the event's direction.
+    /// Only the first press and the release get this; auto-repeat is
+    /// swallowed. The release gets the action of the press even if the
+    /// bindings were swapped in between.
+    Action(KeyAction),
+}
+
+/// A key transition held back and sent late, see [`Engine::take_replay`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Replay {
+    /// Send this scan code in the given direction
+    Key(u16, bool),
+    /// Carry out the action for the given direction, as for
+    /// [`Decision::Action`]
+    Action(KeyAction, bool),
+}
+
+/// The triggers and remaps of a profile, compiled once at load time.
+#[derive(Debug)]
+pub struct Bindings {
+    triggers: TriggerMatcher,
+    sequences: SequenceMatcher,
+    sequence_timeout: Duration,
+    chords: ChordMatcher,
+    /// The base keymap, then the layers; never empty
+    keymaps: Vec<Keymap>,
+}
+
+impl Bindings {
+    /// `triggers` maps key combinations, in the order they must be pressed,
+    /// to blocks; a later duplicate replaces an earlier one. `keymaps` are
+    /// the base remaps followed by the layers, as from
+    /// [`profile::Profile::keymaps`]; a trigger on a key wins over its
+    /// mapping. `ignored` keys may stay held without breaking a trigger.
+    pub fn new(
+        triggers: impl IntoIterator<Item = (Vec<u16>, BlockId)>,
+        mut keymaps: Vec<Keymap>,
+        ignored: Vec<u16>,
+    ) -> Self {
+        if keymaps.is_empty() {
+            keymaps.push(Keymap::default());
+        }
+        Self {
+            sequences: SequenceMatcher::new([], &ignored),
+            triggers: TriggerMatcher::new(triggers, ignored),
+            sequence_timeout: Duration::ZERO,
+            chords: ChordMatcher::default(),
+            keymaps,
+        }
+    }
+
+    /// Add sequence triggers, whose steps have to follow each other within
+    /// `timeout`. An ordinary trigger on a sequence's first step wins over
+    /// the sequence.
+    pub fn with_sequences(
+        mut self,
+        sequences: impl IntoIterator<Item = (Vec<Vec<u16>>, BlockId)>,
+        timeout: Duration,
+    ) -> Self {
+        self.sequences = SequenceMatcher::new(sequences, self.triggers.ignored());
+        self.sequence_timeout = timeout;
+        self
+    }
+
+    /// Add chord triggers: keys that all go down, in any order, within a
+    /// window. An ordinary trigger on one of the keys wins over the chord,
+    /// and a chord wins over a sequence starting with the same key.
+    pub fn with_chords(
+        mut self,
+        chords: impl IntoIterator<Item = (Vec<u16>, Duration, BlockId)>,
+    ) -> Self {
+        self.chords = ChordMatcher::new(chords);
+        self
+    }
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self::new([], Vec::new(), Vec::new())
+    }
+}
+
+/// Key state carried between events. Switching `Bindings` between two events
+/// is fine: keys that are down stay tracked so their releases are still
+/// recognised, and a release always matches what was done with the press.
+#[derive(Debug, Default)]
+pub struct Engine {
+    /// Physical keys currently down, in the order they were pressed
+    held: Vec<u16>,
+    /// Held keys whose press was not passed on to the OS
+    consumed: Vec<(u16, Consumed)>,
+    /// The sequence or chord trigger being typed, if any
+    pending: Option<Pending>,
+    /// Key transitions to send before acting on the last decision
+    replay: Vec<Replay>,
+    /// Layers on while their key is held, with the key
+    momentary: Vec<(u16, usize)>,
+    /// Layers switched on until their key is pressed again
+    toggled: Vec<usize>,
+    /// A layer on for the next key pressed
+    one_shot: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Consumed {
+    /// Ran a trigger block, or is part of a pending trigger
+    Trigger,
+    /// Sent as another key
+    Remap(u16),
+    /// Holds a layer on
+    Layer,
+    /// Started this action
+    Action(KeyAction),
+}
+
+/// Keys held back until a multi-key trigger completes or breaks, or a
+/// dual-role key is decided
+#[derive(Debug)]
+struct Pending {
+    kind: PendingKind,
+    deadline: Instant,
+    /// Keys pressed for the trigger so far
+    keys: Vec<u16>,
+    /// Their transitions, swallowed until the trigger completes or breaks
+    events: Vec<KeyEvent>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PendingKind {
+    /// The step of a sequence reached so far
+    Sequence(NodeId),
+    /// A chord whose first key went down at `start`
+    Chord { start: Instant },
+    /// A dual-role key not yet known to be tapped or held
+    TapHold { key: u16, role: DualRole },
+}
+
+impl Engine {
+    /// Physical keys currently down, in the order they were pressed.
+    pub fn held(&self) -> &[u16] {
+        &self.held
+    }
+
+    /// Key transitions held back for a sequence or chord trigger that then
+    /// broke, or for a dual-role key until it was decided. They are to be
+    /// sent (remaps already applied, actions carried out) before carrying out
+    /// the decision for the last event.
+    pub fn take_replay(&mut self) -> Vec<Replay> {
+        std::mem::take(&mut self.replay)
+    }
+
+    /// When the keys held back time out, if any are. Call [`Engine::poll`]
+    /// then, so a held dual-role key doesn't wait for the next event.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|p| p.deadline)
+    }
+
+    /// Resolve keys held back past their deadline; see
+    /// [`Engine::take_replay`] for the result.
+    pub fn poll(&mut self, bindings: &Bindings, now: Instant) {
+        // A dual-role key replayed may be past its own deadline already
+        while let Some(pending) = &self.pending
+            && now >= pending.deadline
+        {
+            match pending.kind {
+                PendingKind::TapHold { key, role } => {
+                    self.break_pending(bindings, Some((key, role.hold)))
+                }
+                _ => self.break_pending(bindings, None),
+            }
+        }
+    }
+
+    pub fn handle(&mut self, bindings: &Bindings, event: &KeyEvent) -> Decision {
+        if event.injected {
+            return Decision::Pass;
+        }
+        self.poll(bindings, event.timestamp);
+        let decision = self.handle_physical(bindings, event);
+        // Passing the event on would let it overtake the replayed keys
+        if decision == Decision::Pass && !self.replay.is_empty() {
+            return Decision::Remap(event.scancode);
+        }
+        decision
+    }
+
+    fn handle_physical(&mut self, bindings: &Bindings, event: &KeyEvent) -> Decision {
+        let key = event.scancode;
+        if !event.down {
+            self.held.retain(|&k| k != key);
+            self.release_pending(bindings, event);
+            // The OS has to see the release of exactly what it saw pressed
+            let consumed = self.consumed.iter().position(|(k, _)| *k == key);
+            return match consumed.map(|i| self.consumed.swap_remove(i).1) {
+                Some(Consumed::Trigger) => Decision::Swallow,
+                Some(Consumed::Layer) => {
+                    self.momentary.retain(|(k, _)| *k != key);
+                    Decision::Swallow
+                }
+                Some(Consumed::Action(action)) => Decision::Action(action),
+                Some(Consumed::Remap(target)) => Decision::Remap(target),
+                None => Decision::Pass,
+            };
+        }
+
+        // Auto-repeat keeps the original press position
+        let repeat = self.held.contains(&key);
+        if !repeat {
+            self.held.push(key);
+            if let Some(decision) = self.continue_pending(bindings, event) {
+                return decision;
+            }
+        }
+
+        let layer = self.layer(bindings, key);
+        let keymap = &bindings.keymaps[layer];
+        let layer_key = keymap.layer_key(key);
+        // Used up by this key, whatever it turns out to be, but left for it
+        // to be replayed in if it is held back
+        let one_shot = self.one_shot;
+        if !repeat && layer_key.is_none() {
+            self.one_shot = None;
+        }
+        let consumed = self.consumed.iter().position(|(k, _)| *k == key);
+        if let Some(block) = bindings.triggers.find(&self.held, key) {
+            if consumed.is_none() && !repeat {
+                self.consumed.push((key, Consumed::Trigger));
+            }
+            return Decision::Run(block);
+        }
+        if !repeat {
+            // Chords only start with nothing else held
+            if bindings.chords.contains(key)
+                && self
+                    .held
+                    .iter()
+                    .all(|k| *k == key || bindings.triggers.ignored().contains(k))
+                && let Some(ChordProgress::Partial(window)) =
+                    bindings.chords.progress(&[key], Duration::ZERO)
+            {
+                let kind = PendingKind::Chord {
+                    start: event.timestamp,
+                };
+                self.hold_back(event, kind, event.timestamp + window);
+                self.one_shot = one_shot;
+                return Decision::Swallow;
+            }
+            if let Some(node) = bindings
+                .sequences
+                .step(SequenceMatcher::ROOT, &self.held, key)
+            {
+                let decision = self.enter_step(bindings, event, node);
+                if decision == Decision::Swallow {
+                    self.one_shot = one_shot;
+                }
+                return decision;
+            }
+            if let Some(layer_key) = layer_key {
+                self.switch_layer(key, layer_key);
+                return Decision::Swallow;
+            }
+            if let Some(role) = keymap.dual_role(key) {
+                let kind = PendingKind::TapHold { key, role };
+                self.hold_back(event, kind, event.timestamp + role.tapping_term);
+                self.one_shot = one_shot;
+                return Decision::Swallow;
+            }
+        }
+        match consumed.map(|i| &self.consumed[i].1) {
+            Some(Consumed::Trigger | Consumed::Layer | Consumed::Action(_)) => Decision::Swallow,
+            Some(&Consumed::Remap(target)) => Decision::Remap(target),
+            // A repeat of a key that went through as itself stays itself
+            None if repeat => Decision::Pass,
+            None => {
+                if let Some(index) = keymap.action_index(key) {
+                    let action = keymap.actions[index].1.clone();
+                    self.consumed.push((key, Consumed::Action(action.clone())));
+                    return Decision::Action(action);
+                }
+                match keymap.remaps.get(key) {
+                    Some(target) => {
+                        self.consumed.push((key, Consumed::Remap(target)));
+                        Decision::Remap(target)
+                    }
+                    None => Decision::Pass,
+                }
+            }
+        }
+    }
+
+    // The decision for a key that continues the pending trigger, or `None`
+    // (after breaking it) when the key doesn't
+    fn continue_pending(&mut self, bindings: &Bindings, event: &KeyEvent) -> Option<Decision> {
+        let pending = self.pending.as_ref()?;
+        let key = event.scancode;
+        match pending.kind {
+            PendingKind::Sequence(node) => {
+                // Keys of earlier steps may still be held
+                let held: Vec<u16> = self
+                    .held
+                    .iter()
+                    .copied()
+                    .filter(|k| *k == key || !pending.keys.contains(k))
+                    .collect();
+                if let Some(next) = bindings.sequences.step(node, &held, key) {
+                    return Some(self.enter_step(bindings, event, next));
+                }
+            }
+            PendingKind::Chord { start } => {
+                let mut pressed = pending.keys.clone();
+                pressed.push(key);
+                let elapsed = event.timestamp.saturating_duration_since(start);
+                match bindings.chords.progress(&pressed, elapsed) {
+                    Some(ChordProgress::Complete(block)) => {
+                        self.consumed.push((key, Consumed::Trigger));
+                        self.pending = None;
+                        self.one_shot = None;
+                        return Some(Decision::Run(block));
+                    }
+                    Some(ChordProgress::Partial(window)) => {
+                        self.hold_back(event, pending.kind, start + window);
+                        return Some(Decision::Swallow);
+                    }
+                    None => {}
+                }
+            }
+            PendingKind::TapHold { key: th, role } => {
+                if role.permissive_hold {
+                    // Decided; the key itself is handled as usual
+                    self.break_pending(bindings, Some((th, role.hold)));
+                    return self.continue_pending(bindings, event);
+                }
+                // Held back too, until the tapping term decides
+                self.hold_back(event, pending.kind, pending.deadline);
+                return Some(Decision::Swallow);
+            }
+        }
+        self.break_pending(bindings, None);
+        // A dual-role key among the replayed ones may be pending again
+        self.continue_pending(bindings, event)
+    }
+
+    // A key going up while keys are held back: it breaks a chord, decides a
+    // dual-role key tapped, or is held back as well. `true` when held back.
+    fn release_pending(&mut self, bindings: &Bindings, event: &KeyEvent) -> bool {
+        let key = event.scancode;
+        while let Some(pending) = &mut self.pending
+            && pending.keys.contains(&key)
+        {
+            match pending.kind {
+                // Released before the chord was complete
+                PendingKind::Chord { .. } => self.break_pending(bindings, None),
+                PendingKind::TapHold { key: k, role } if k == key => {
+                    self.break_pending(bindings, Some((key, role.tap)))
+                }
+                _ => {
+                    pending.events.push(*event);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn enter_step(&mut self, bindings: &Bindings, event: &KeyEvent, node: NodeId) -> Decision {
+        if let Some(block) = bindings.sequences.block(node) {
+            self.consumed.push((event.scancode, Consumed::Trigger));
+            self.pending = None;
+            self.one_shot = None;
+            return Decision::Run(block);
+        }
+        let deadline = event.timestamp + bindings.sequence_timeout;
+        self.hold_back(event, PendingKind::Sequence(node), deadline);
+        Decision::Swallow
+    }
+
+    // Add a key press to the pending trigger, starting one if needed
+    fn hold_back(&mut self, event: &KeyEvent, kind: PendingKind, deadline: Instant) {
+        self.consumed.push((event.scancode, Consumed::Trigger));
+        let pending = self.pending.get_or_insert_with(|| Pending {
+            kind,
+            deadline,
+            keys: Vec::new(),
+            events: Vec::new(),
+        });
+        pending.kind = kind;
+        pending.deadline = deadline;
+        pending.keys.push(event.scancode);
+        pending.events.push(*event);
+    }
+
+    // The highest active layer that maps `key`, or the base
+    fn layer(&self, bindings: &Bindings, key: u16) -> usize {
+        let active = |layer: usize| {
+            self.one_shot == Some(layer)
+                || self.toggled.contains(&layer)
+                || self.momentary.iter().any(|(_, l)| *l == layer)
+        };
+        (1..bindings.keymaps.len())
+            .rev()
+            .find(|&layer| active(layer) && bindings.keymaps[layer].maps(key))
+            .unwrap_or(0)
+    }
+
+    fn switch_layer(&mut self, key: u16, layer_key: LayerKey) {
+        let LayerKey { layer, mode } = layer_key;
+        let consumed = match mode {
+            LayerMode::Momentary => {
+                self.momentary.push((key, layer));
+                Consumed::Layer
+            }
+            LayerMode::Toggle => {
+                match self.toggled.iter().position(|l| *l == layer) {
+                    Some(i) => {
+                        self.toggled.remove(i);
+                    }
+                    None => self.toggled.push(layer),
+                }
+                Consumed::Trigger
+            }
+            LayerMode::OneShot => {
+                self.one_shot = Some(layer);
+                Consumed::Trigger
+            }
+        };
+        self.consumed.push((key, consumed));
+    }
+
+    // Give up on the pending trigger, or decide the dual-role key, and queue
+    // the held-back keys for replay. `resolved` is a key and what it turns
+    // into; the others are handled as they would have been without the
+    // pending trigger.
+    fn break_pending(&mut self, bindings: &Bindings, resolved: Option<(u16, u16)>) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        // A dual-role key tapped while other keys went down: the whole tap
+        // comes first rather than around them
+        let tapped = resolved
+            .map(|(k, _)| k)
+            .filter(|k| !self.held.contains(k) && pending.keys.len() > 1);
+        for event in &pending.events {
+            let key = event.scancode;
+            if event.down {
+                // The hold-back's, if the key is still held
+                self.consumed.retain(|(k, _)| *k != key);
+            }
+            // Another dual-role key replayed waits to be decided in turn, and
+            // the keys after it with it
+            if self.pending.is_some() {
+                if event.down {
+                    if self.continue_pending(bindings, event).is_some() {
+                        continue;
+                    }
+                } else if self.release_pending(bindings, event) {
+                    self.consumed.retain(|(k, _)| *k != key);
+                    continue;
+                }
+            }
+            match resolved {
+                Some((k, target)) if k == key && event.down => {
+                    self.one_shot = None;
+                    if Some(key) == tapped {
+                        self.replay
+                            .extend([Replay::Key(target, true), Replay::Key(target, false)]);
+                        // Its release was replayed already
+                        self.consumed.push((key, Consumed::Trigger));
+                    } else {
+                        self.replay.push(Replay::Key(target, true));
+                        self.consumed.push((key, Consumed::Remap(target)));
+                    }
+                }
+                _ => self.replay_event(bindings, event),
+            }
+        }
+    }
+
+    // A held-back key transition as it would have gone without the pending
+    // trigger, in the layers on now: those of the keys replayed before it
+    fn replay_event(&mut self, bindings: &Bindings, event: &KeyEvent) {
+        let key = event.scancode;
+        if !event.down {
+            // What the replayed press did
+            let consumed = self.consumed.iter().position(|(k, _)| *k == key);
+            match consumed.map(|i| self.consumed.swap_remove(i).1) {
+                Some(Consumed::Remap(target)) => self.replay.push(Replay::Key(target, false)),
+                Some(Consumed::Action(action)) => self.replay.push(Replay::Action(action, false)),
+                Some(Consumed::Layer) => self.momentary.retain(|(k, _)| *k != key),
+                Some(_) => {}
+                None => self.replay.push(Replay::Key(key, false)),
+            }
+            return;
+        }
+        let keymap = &bindings.keymaps[self.layer(bindings, key)];
+        if let Some(layer_key) = keymap.layer_key(key) {
+            self.switch_layer(key, layer_key);
+            return;
+        }
+        if let Some(role) = keymap.dual_role(key) {
+            let kind = PendingKind::TapHold { key, role };
+            self.hold_back(event, kind, event.timestamp + role.tapping_term);
+            return;
+        }
+        self.one_shot = None;
+        if let Some(index) = keymap.action_index(key) {
+            let action = keymap.actions[index].1.clone();
+            self.replay.push(Replay::Action(action.clone(), true));
+            self.consumed.push((key, Consumed::Action(action)));
+            return;
+        }
+        match keymap.remaps.get(key) {
+            Some(target) => {
+                self.replay.push(Replay::Key(target, true));
+                self.consumed.push((key, Consumed::Remap(target)));
+            }
+            None => self.replay.push(Replay::Key(key, true)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use profile::RemapTable;
+
+    const A: u16 = 0x1E;
+    const B: u16 = 0x30;
+    const C: u16 = 0x2E;
+    const SHIFT: u16 = 0x2A;
+    const CAPS: u16 = 0x3A;
+    const HENKAN: u16 = 0x79;
+    const F: u16 = 0x21;
+    const G: u16 = 0x22;
+    const O: u16 = 0x18;
+    const SPACE: u16 = 0x39;
+    const J: u16 = 0x24;
+    const K: u16 = 0x25;
+    const ESC: u16 = 0x01;
+    const CTRL: u16 = 0x1D;
+    const LEFT: u16 = 0xE04B;
+
+    fn down(scancode: u16) -> KeyEvent {
+        KeyEvent::new(scancode, true)
+    }
+
+    fn up(scancode: u16) -> KeyEvent {
+        KeyEvent::new(scancode, false)
+    }
+
+    struct Harness {
+        engine: Engine,
+        bindings: Bindings,
+    }
+
+    impl Harness {
+        fn handle(&mut self, event: &KeyEvent) -> Decision {
+            self.engine.handle(&self.bindings, event)
+        }
+
+        fn feed(&mut self, events: &[KeyEvent]) -> Vec<Decision> {
+            events.iter().map(|e| self.handle(e)).collect()
+        }
+
+        fn held(&self) -> &[u16] {
+            self.engine.held()
+        }
+    }
+
+    fn bindings(triggers: &[(&[u16], BlockId)], remaps: &[(u16, u16)]) -> Bindings {
+        Bindings::new(
+            triggers.iter().map(|(combo, id)| (combo.to_vec(), *id)),
+            vec![remaps.iter().copied().collect::<RemapTable>().into()],
+            profile::default_ignored_for_matching("JIS").to_vec(),
+        )
+    }
+
+    fn engine(triggers: &[(&[u16], BlockId)], remaps: &[(u16, u16)]) -> Harness {
+        Harness {
+            engine: Engine::default(),
+            bindings: bindings(triggers, remaps),
+        }
+    }
+
+    // Each sequence is a list of single-key steps; 1s timeout
+    fn sequences(
+        triggers: &[(&[u16], BlockId)],
+        sequences: &[(&[u16], BlockId)],
+        remaps: &[(u16, u16)],
+    ) -> Harness {
+        let steps = sequences
+            .iter()
+            .map(|(keys, id)| (keys.iter().map(|&k| vec![k]).collect(), *id));
+        Harness {
+            engine: Engine::default(),
+            bindings: bindings(triggers, remaps).with_sequences(steps, Duration::from_secs(1)),
+        }
+    }
+
+    // J + K within 50ms
+    fn chord() -> Harness {
+        Harness {
+            engine: Engine::default(),
+            bindings: bindings(&[], &[]).with_chords([(vec![J, K], Duration::from_millis(50), 0)]),
+        }
+    }
+
+    // Escape on tap, Ctrl on hold, 200ms
+    fn esc_ctrl(permissive_hold: bool) -> DualRole {
+        DualRole {
+            tap: ESC,
+            hold: CTRL,
+            tapping_term: Duration::from_millis(200),
+            permissive_hold,
+        }
+    }
+
+    // CapsLock: `esc_ctrl`
+    fn tap_hold(permissive_hold: bool) -> Harness {
+        let keymap = Keymap {
+            dual_roles: vec![(CAPS, esc_ctrl(permissive_hold))],
+            ..Default::default()
+        };
+        Harness {
+            engine: Engine::default(),
+            bindings: Bindings::new([], vec![keymap], vec![]),
+        }
+    }
+
+    // Layer 1 maps J to Left; Space holds it on, G toggles it and F sets it
+    // one-shot. The base maps J to K and A to B.
+    fn layers() -> Harness {
+        let switch = |mode| LayerKey { layer: 1, mode };
+        let base = Keymap {
+            remaps: [(J, K), (A, B)].into_iter().collect(),
+            layer_keys: vec![
+                (F, switch(LayerMode::OneShot)),
+                (G, switch(LayerMode::Toggle)),
+                (SPACE, switch(LayerMode::Momentary)),
+            ],
+            ..Default::default()
+        };
+        let nav = Keymap::from([(J, LEFT)].into_iter().collect::<RemapTable>());
+        Harness {
+            engine: Engine::default(),
+            bindings: Bindings::new([], vec![base, nav], vec![]),
+        }
+    }
+
+    fn after(event: KeyEvent, start: Instant, ms: u64) -> KeyEvent {
+        KeyEvent {
+            timestamp: start + Duration::from_millis(ms),
+            ..event
+        }
+    }
+
+    #[test]
+    fn test_single_key_trigger() {
+        let mut e = engine(&[(&[A], 0)], &[]);
+        let decisions = e.feed(&[down(A), up(A), down(B), up(B)]);
+        assert_eq!(
+            decisions,
+            [
+                Decision::Run(0),
+                Decision::Swallow,
+                Decision::Pass,
+                Decision::Pass
+            ]
+        );
+        assert!(e.held().is_empty());
+    }
+
+    #[test]
+    fn test_combo_fires_on_its_last_key() {
+        let mut e = engine(&[(&[SHIFT, A], 7)], &[]);
+        let decisions = e.feed(&[down(SHIFT), down(A), up(A), up(SHIFT)]);
+        assert_eq!(
+            decisions,
+            [
+                Decision::Pass,
+                Decision::Run(7),
+                Decision::Swallow,
+                Decision::Pass
+            ]
+        );
+    }
+
+    #[test]
+    fn test_combo_requires_press_order() {
+        let mut e = engine(&[(&[A, B], 0), (&[B, A], 1)], &[]);
+        assert_eq!(e.feed(&[down(B), down(A)])[1], Decision::Run(1));
+        e.feed(&[up(A), up(B)]);
+        assert_eq!(e.feed(&[down(A), down(B)])[1], Decision::Run(0));
+    }
+
+    #[test]
+    fn test_combo_does_not_fire_on_an_earlier_key() {
+        let mut e = engine(&[(&[A, B], 0)], &[]);
+        // B held first, then A: A is not the combo's last key
+        assert_eq!(e.feed(&[down(B), down(A)]), vec![Decision::Pass; 2]);
+    }
+
+    #[test]
+    fn test_longest_match_wins() {
+        let mut e = engine(&[(&[C], 0), (&[SHIFT, C], 1), (&[SHIFT, A, C], 2)], &[]);
+        assert_eq!(e.feed(&[down(C)]), [Decision::Run(0)]);
+        e.feed(&[up(C)]);
+        assert_eq!(e.feed(&[down(SHIFT), down(C)])[1], Decision::Run(1));
+        e.feed(&[up(C)]);
+        assert_eq!(e.feed(&[down(A), down(C)])[1], Decision::Run(2));
+    }
+
+    #[test]
+    fn test_extra_held_key_blocks_match() {
+        let mut e = engine(&[(&[A], 0)], &[]);
+        assert_eq!(e.feed(&[down(B), down(A)]), vec![Decision::Pass; 2]);
+    }
+
+    #[test]
+    fn test_status_keys_may_stay_held() {
+        let mut e = engine(&[(&[A], 0), (&[HENKAN, B], 1)], &[]);
+        let decisions = e.feed(&[down(CAPS), down(A), down(HENKAN), up(A), down(B)]);
+        assert_eq!(decisions[1], Decision::Run(0));
+        assert_eq!(decisions[4], Decision::Run(1));
+    }
+
+    #[test]
+    fn test_tie_goes_to_lower_block_id() {
+        let mut e = engine(&[(&[CAPS, A], 3), (&[HENKAN, A], 2)], &[]);
+        let decisions = e.feed(&[down(CAPS), down(HENKAN), down(A)]);
+        assert_eq!(decisions[2], Decision::Run(2));
+    }
+
+    #[test]
+    fn test_remap_both_directions() {
+        let mut e = engine(&[], &[(A, B)]);
+        let decisions = e.feed(&[down(A), up(A), down(C)]);
+        assert_eq!(
+            decisions,
+            [Decision::Remap(B), Decision::Remap(B), Decision::Pass]
+        );
+    }
+
+    #[test]
+    fn test_trigger_takes_precedence_over_remap() {
+        let mut e = engine(&[(&[A], 0)], &[(A, B)]);
+        assert_eq!(
+            e.feed(&[down(A), up(A)]),
+            [Decision::Run(0), Decision::Swallow]
+        );
+    }
+
+    #[test]
+    fn test_overlapping_combos_swallow_their_releases() {
+        let mut e = engine(&[(&[A], 0), (&[A, B], 1)], &[]);
+        let decisions = e.feed(&[down(A), down(B), down(C), up(A), up(C), up(B)]);
+        assert_eq!(
+            decisions,
+            [
+                Decision::Run(0),
+                Decision::Run(1),
+                Decision::Pass,
+                Decision::Swallow,
+                Decision::Pass,
+                Decision::Swallow
+            ]
+        );
+        assert!(e.engine.consumed.is_empty());
+    }
+
+    #[test]
+    fn test_repeat_of_consumed_key_is_swallowed() {
+        let mut e = engine(&[(&[A], 0)], &[]);
+        // B pressed after A stops the trigger, but the OS never saw A go down
+        let decisions = e.feed(&[down(A), down(B), down(A), up(A)]);
+        assert_eq!(
+            decisions,
+            [
+                Decision::Run(0),
+                Decision::Pass,
+                Decision::Swallow,
+                Decision::Swallow
+            ]
+        );
+    }
+
+    #[test]
+    fn test_release_of_passed_key_passes_after_repeat_runs() {
+        let mut e = engine(&[(&[A], 0)], &[]);
+        let decisions = e.feed(&[down(B), down(A), up(B), down(A), up(A)]);
+        assert_eq!(decisions[1], Decision::Pass);
+        assert_eq!(decisions[3], Decision::Run(0));
+        assert_eq!(decisions[4], Decision::Pass);
+    }
+
+    #[test]
+    fn test_release_follows_the_press_across_bindings_swap() {
+        let mut e = engine(&[], &[(A, B)]);
+        e.feed(&[down(A), down(C)]);
+        e.bindings = bindings(&[], &[(A, C), (C, B)]);
+        assert_eq!(
+            e.feed(&[up(A), up(C)]),
+            [Decision::Remap(B), Decision::Pass]
+        );
+    }
+
+    #[test]
+    fn test_injected_events_pass_untracked() {
+        let mut e = engine(&[(&[A], 0)], &[(A, B)]);
+        let injected = KeyEvent {
+            injected: true,
+            ..down(A)
+        };
+        assert_eq!(e.handle(&injected), Decision::Pass);
+        assert!(e.held().is_empty());
+    }
+
+    #[test]
+    fn test_auto_repeat_keeps_press_order() {
+        let mut e = engine(&[(&[A, B], 0)], &[]);
+        let decisions = e.feed(&[down(A), down(B), down(A), down(B)]);
+        assert_eq!(e.held(), [A, B]);
+        // Each repeat of the last key fires the trigger again
+        assert_eq!(decisions[3], Decision::Run(0));
+    }
+
+    #[test]
+    fn test_swapping_bindings_keeps_held_keys() {
+        let mut e = engine(&[(&[A], 0)], &[]);
+        e.feed(&[down(SHIFT)]);
+        e.bindings = bindings(&[(&[SHIFT, A], 5)], &[]);
+        assert_eq!(e.handle(&down(A)), Decision::Run(5));
+    }
+
+    #[test]
+    fn test_sequence_fires_and_swallows_its_keys() {
+        let mut e = sequences(&[], &[(&[G, G], 0)], &[]);
+        let decisions = e.feed(&[down(G), up(G), down(G), up(G)]);
+        assert_eq!(
+            decisions,
+            [
+                Decision::Swallow,
+                Decision::Swallow,
+                Decision::Run(0),
+                Decision::Swallow
+            ]
+        );
+        assert!(e.engine.take_replay().is_empty());
+    }
+
+    #[test]
+    fn test_sequence_steps_may_overlap() {
+        let mut e = sequences(&[], &[(&[SPACE, F, O], 0)], &[]);
+        let decisions = e.feed(&[down(SPACE), down(F), up(SPACE), down(O)]);
+        assert_eq!(decisions[3], Decision::Run(0));
+    }
+
+    #[test]
+    fn test_broken_sequence_is_replayed() {
+        let mut e = sequences(&[], &[(&[SPACE, F], 0)], &[]);
+        let decisions = e.feed(&[down(SPACE), up(SPACE), down(A)]);
+        assert_eq!(decisions[..2], vec![Decision::Swallow; 2]);
+        // Sent after the replay so it keeps its place
+        assert_eq!(decisions[2], Decision::Remap(A));
+        assert_eq!(
+            e.engine.take_replay(),
+            [Replay::Key(SPACE, true), Replay::Key(SPACE, false)]
+        );
+        assert_eq!(e.handle(&up(A)), Decision::Pass);
+    }
+
+    #[test]
+    fn test_replayed_key_still_held_is_remapped() {
+        let mut e = sequences(&[], &[(&[A, B], 0)], &[(A, C)]);
+        let decisions = e.feed(&[down(A), down(G)]);
+        assert_eq!(decisions, [Decision::Swallow, Decision::Remap(G)]);
+        assert_eq!(e.engine.take_replay(), [Replay::Key(C, true)]);
+        assert_eq!(e.handle(&up(A)), Decision::Remap(C));
+    }
+
+    #[test]
+    fn test_sequence_times_out() {
+        let mut e = sequences(&[], &[(&[G, G], 0)], &[]);
+        let start = Instant::now();
+        let decisions = e.feed(&[
+            after(down(G), start, 0),
+            after(up(G), start, 100),
+            after(down(G), start, 1500),
+        ]);
+        // The late G starts the sequence over
+        assert_eq!(decisions, vec![Decision::Swallow; 3]);
+        assert_eq!(
+            e.engine.take_replay(),
+            [Replay::Key(G, true), Replay::Key(G, false)]
+        );
+        e.handle(&after(up(G), start, 1550));
+        assert_eq!(e.handle(&after(down(G), start, 1600)), Decision::Run(0));
+    }
+
+    #[test]
+    fn test_trigger_on_first_step_wins_over_sequence() {
+        let mut e = sequences(&[(&[G], 1)], &[(&[G, G], 0)], &[]);
+        assert_eq!(e.feed(&[down(G), up(G), down(G)])[2], Decision::Run(1));
+    }
+
+    #[test]
+    fn test_chord_fires_in_either_order() {
+        let mut e = chord();
+        let decisions = e.feed(&[down(K), down(J), up(K), up(J)]);
+        assert_eq!(
+            decisions,
+            [
+                Decision::Swallow,
+                Decision::Run(0),
+                Decision::Swallow,
+                Decision::Swallow
+            ]
+        );
+        assert_eq!(
+            e.feed(&[down(J), down(K)]),
+            [Decision::Swallow, Decision::Run(0)]
+        );
+    }
+
+    #[test]
+    fn test_tapped_chord_key_is_replayed() {
+        let mut e = chord();
+        assert_eq!(e.handle(&down(J)), Decision::Swallow);
+        assert_eq!(e.handle(&up(J)), Decision::Remap(J));
+        assert_eq!(e.engine.take_replay(), [Replay::Key(J, true)]);
+        assert!(e.held().is_empty());
+    }
+
+    #[test]
+    fn test_chord_outside_window_is_replayed() {
+        let mut e = chord();
+        let start = Instant::now();
+        let decisions = e.feed(&[after(down(J), start, 0), after(down(K), start, 80)]);
+        // K is not a new chord start while J is held
+        assert_eq!(decisions, [Decision::Swallow, Decision::Remap(K)]);
+        assert_eq!(e.engine.take_replay(), [Replay::Key(J, true)]);
+        assert_eq!(e.handle(&up(J)), Decision::Pass);
+    }
+
+    #[test]
+    fn test_other_key_breaks_chord() {
+        let mut e = chord();
+        assert_eq!(
+            e.feed(&[down(J), down(A)]),
+            [Decision::Swallow, Decision::Remap(A)]
+        );
+        assert_eq!(e.engine.take_replay(), [Replay::Key(J, true)]);
+    }
+
+    #[test]
+    fn test_dual_role_tap() {
+        let mut e = tap_hold(false);
+        let start = Instant::now();
+        assert_eq!(e.handle(&after(down(CAPS), start, 0)), Decision::Swallow);
+        assert_eq!(e.handle(&after(up(CAPS), start, 100)), Decision::Remap(ESC));
+        assert_eq!(e.engine.take_replay(), [Replay::Key(ESC, true)]);
+        assert!(e.engine.consumed.is_empty());
+    }
+
+    #[test]
+    fn test_dual_role_hold_past_tapping_term() {
+        let mut e = tap_hold(false);
+        let start = Instant::now();
+        let decisions = e.feed(&[after(down(CAPS), start, 0), after(down(A), start, 300)]);
+        // Ctrl goes down before A
+        assert_eq!(decisions, [Decision::Swallow, Decision::Remap(A)]);
+        assert_eq!(e.engine.take_replay(), [Replay::Key(CTRL, true)]);
+        assert_eq!(
+            e.feed(&[after(up(A), start, 350), after(up(CAPS), start, 400)]),
+            [Decision::Pass, Decision::Remap(CTRL)]
+        );
+    }
+
+    #[test]
+    fn test_dual_role_hold_on_poll() {
+        let mut e = tap_hold(false);
+        let start = Instant::now();
+        e.handle(&after(down(CAPS), start, 0));
+        let deadline = e.engine.deadline().unwrap();
+        assert_eq!(deadline, start + Duration::from_millis(200));
+        e.engine
+            .poll(&e.bindings, start + Duration::from_millis(100));
+        assert!(e.engine.take_replay().is_empty());
+        e.engine.poll(&e.bindings, deadline);
+        assert_eq!(e.engine.take_replay(), [Replay::Key(CTRL, true)]);
+        assert_eq!(e.engine.deadline(), None);
+        // Auto-repeat keeps sending the hold key
+        assert_eq!(
+            e.handle(&after(down(CAPS), start, 500)),
+            Decision::Remap(CTRL)
+        );
+    }
+
+    #[test]
+    fn test_dual_role_permissive_hold() {
+        let mut e = tap_hold(true);
+        assert_eq!(
+            e.feed(&[down(CAPS), down(A)]),
+            [Decision::Swallow, Decision::Remap(A)]
+        );
+        assert_eq!(e.engine.take_replay(), [Replay::Key(CTRL, true)]);
+        assert_eq!(e.handle(&up(CAPS)), Decision::Remap(CTRL));
+    }
+
+    #[test]
+    fn test_dual_role_tap_replays_keys_pressed_meanwhile() {
+        let mut e = tap_hold(false);
+        let decisions = e.feed(&[down(CAPS), down(A), up(A), up(CAPS)]);
+        assert_eq!(decisions, vec![Decision::Swallow; 4]);
+        // The tap is not wrapped around A
+        assert_eq!(
+            e.engine.take_replay(),
+            [
+                Replay::Key(ESC, true),
+                Replay::Key(ESC, false),
+                Replay::Key(A, true),
+                Replay::Key(A, false)
+            ]
+        );
+        assert!(e.held().is_empty());
+        assert!(e.engine.consumed.is_empty());
+    }
+
+    #[test]
+    fn test_dual_role_tap_before_a_key_still_held() {
+        let mut e = tap_hold(false);
+        let decisions = e.feed(&[down(CAPS), down(A), up(CAPS)]);
+        assert_eq!(decisions, vec![Decision::Swallow; 3]);
+        assert_eq!(
+            e.engine.take_replay(),
+            [
+                Replay::Key(ESC, true),
+                Replay::Key(ESC, false),
+                Replay::Key(A, true)
+            ]
+        );
+        assert_eq!(e.handle(&up(A)), Decision::Pass);
+    }
+
+    #[test]
+    fn test_second_dual_role_key_is_decided_in_turn() {
+        let mut e = tap_hold(false);
+        let shift = DualRole {
+            tap: A,
+            hold: SHIFT,
+            ..esc_ctrl(false)
+        };
+        // Kept sorted by scan code
+        e.bindings.keymaps[0].dual_roles.insert(0, (A, shift));
+        let start = Instant::now();
+        e.feed(&[after(down(CAPS), start, 0), after(down(A), start, 50)]);
+        e.engine
+            .poll(&e.bindings, start + Duration::from_millis(200));
+        assert_eq!(e.engine.take_replay(), [Replay::Key(CTRL, true)]);
+        // A has a tapping term of its own
+        let deadline = e.engine.deadline().unwrap();
+        assert_eq!(deadline, start + Duration::from_millis(250));
+        e.engine.poll(&e.bindings, deadline);
+        assert_eq!(e.engine.take_replay(), [Replay::Key(SHIFT, true)]);
+        assert_eq!(
+            e.feed(&[after(up(A), start, 300), after(up(CAPS), start, 300)]),
+            [Decision::Remap(SHIFT), Decision::Remap(CTRL)]
+        );
+
+        let decisions = e.feed(&[down(CAPS), down(A), up(A), up(CAPS)]);
+        assert_eq!(decisions, vec![Decision::Swallow; 4]);
+        assert_eq!(
+            e.engine.take_replay(),
+            [
+                Replay::Key(ESC, true),
+                Replay::Key(ESC, false),
+                Replay::Key(A, true),
+                Replay::Key(A, false)
+            ]
+        );
+        assert!(e.engine.consumed.is_empty());
+        assert_eq!(e.engine.deadline(), None);
+    }
+
+    #[test]
+    fn test_momentary_layer() {
+        let mut e = layers();
+        let decisions = e.feed(&[down(SPACE), down(J), up(J), down(A), up(A), up(SPACE)]);
+        assert_eq!(
+            decisions,
+            [
+                Decision::Swallow,
+                Decision::Remap(LEFT),
+                Decision::Remap(LEFT),
+                // Not in the layer: falls through to the base
+                Decision::Remap(B),
+                Decision::Remap(B),
+                Decision::Swallow
+            ]
+        );
+        assert_eq!(e.handle(&down(J)), Decision::Remap(K));
+    }
+
+    #[test]
+    fn test_release_after_layer_is_off_matches_the_press() {
+        let mut e = layers();
+        e.feed(&[down(SPACE), down(J), up(SPACE)]);
+        assert_eq!(e.handle(&up(J)), Decision::Remap(LEFT));
+    }
+
+    #[test]
+    fn test_toggled_layer() {
+        let mut e = layers();
+        e.feed(&[down(G), up(G)]);
+        assert_eq!(e.feed(&[down(J), up(J)])[0], Decision::Remap(LEFT));
+        e.feed(&[down(G), up(G)]);
+        assert_eq!(e.handle(&down(J)), Decision::Remap(K));
+    }
+
+    #[test]
+    fn test_one_shot_layer() {
+        let mut e = layers();
+        assert_eq!(
+            e.feed(&[down(F), up(F)]),
+            [Decision::Swallow, Decision::Swallow]
+        );
+        assert_eq!(e.feed(&[down(J), up(J)])[0], Decision::Remap(LEFT));
+        assert_eq!(e.handle(&down(J)), Decision::Remap(K));
+    }
+
+    #[test]
+    fn test_one_shot_layer_applies_to_a_key_held_back() {
+        let mut e = layers();
+        e.bindings = e
+            .bindings
+            .with_sequences([(vec![vec![J], vec![O]], 0)], Duration::from_secs(1));
+        e.feed(&[down(F), up(F)]);
+        assert_eq!(e.feed(&[down(J), up(J)]), vec![Decision::Swallow; 2]);
+        // Breaks the sequence: J was pressed with the layer on, A after it
+        assert_eq!(e.handle(&down(A)), Decision::Remap(B));
+        assert_eq!(
+            e.engine.take_replay(),
+            [Replay::Key(LEFT, true), Replay::Key(LEFT, false)]
+        );
+    }
+
+    #[test]
+    fn test_one_shot_layer_then_dual_role_key() {
+        let mut e = layers();
+        e.bindings.keymaps[1]
+            .dual_roles
+            .push((CAPS, esc_ctrl(false)));
+        e.feed(&[down(F), up(F)]);
+        let decisions = e.feed(&[down(CAPS), down(J), up(J), up(CAPS)]);
+        assert_eq!(decisions, vec![Decision::Swallow; 4]);
+        // The layer was used up by CapsLock, not J
+        assert_eq!(
+            e.engine.take_replay(),
+            [
+                Replay::Key(ESC, true),
+                Replay::Key(ESC, false),
+                Replay::Key(K, true),
+                Replay::Key(K, false)
+            ]
+        );
+
+        let start = Instant::now();
+        e.feed(&[after(down(F), start, 0), after(up(F), start, 0)]);
+        e.handle(&after(down(CAPS), start, 10));
+        e.engine
+            .poll(&e.bindings, start + Duration::from_millis(300));
+        assert_eq!(e.engine.take_replay(), [Replay::Key(CTRL, true)]);
+        assert_eq!(
+            e.handle(&after(up(CAPS), start, 400)),
+            Decision::Remap(CTRL)
+        );
+    }
+
+    #[test]
+    fn test_layer_key_held_back_switches_the_layer() {
+        let mut e = layers();
+        e.bindings.keymaps[0]
+            .dual_roles
+            .push((CAPS, esc_ctrl(false)));
+        let start = Instant::now();
+        e.feed(&[after(down(CAPS), start, 0), after(down(SPACE), start, 50)]);
+        e.engine
+            .poll(&e.bindings, start + Duration::from_millis(200));
+        assert_eq!(e.engine.take_replay(), [Replay::Key(CTRL, true)]);
+        assert_eq!(e.handle(&after(down(J), start, 250)), Decision::Remap(LEFT));
+        let decisions = e.feed(&[
+            after(up(J), start, 300),
+            after(up(SPACE), start, 300),
+            after(up(CAPS), start, 300),
+        ]);
+        assert_eq!(
+            decisions,
+            [
+                Decision::Remap(LEFT),
+                Decision::Swallow,
+                Decision::Remap(CTRL)
+            ]
+        );
+
+        // Keys held back after it are in the layer too
+        e.feed(&[down(CAPS), down(SPACE), down(J), up(J), up(SPACE), up(CAPS)]);
+        assert_eq!(
+            e.engine.take_replay(),
+            [
+                Replay::Key(ESC, true),
+                Replay::Key(ESC, false),
+                Replay::Key(LEFT, true),
+                Replay::Key(LEFT, false)
+            ]
+        );
+        assert!(e.engine.momentary.is_empty());
+    }
+
+    #[test]
+    fn test_action_on_press_and_release_only() {
+        let undo = KeyAction::Combo(vec![CTRL, 0x2C]);
+        let keymap = Keymap {
+            actions: vec![(A, undo.clone())],
+            ..Default::default()
+        };
+        let mut e = Harness {
+            engine: Engine::default(),
+            bindings: Bindings::new([], vec![keymap], vec![]),
+        };
+        let decisions = e.feed(&[down(A), down(A), down(A), up(A)]);
+        assert_eq!(
+            decisions,
+            [
+                Decision::Action(undo.clone()),
+                Decision::Swallow,
+                Decision::Swallow,
+                Decision::Action(undo)
+            ]
+        );
+        assert!(e.engine.consumed.is_empty());
+    }
+
+    #[test]
+    fn test_action_key_held_back_is_replayed_as_the_action() {
+        let undo = KeyAction::Combo(vec![CTRL, 0x2C]);
+        let mut e = tap_hold(false);
+        e.bindings.keymaps[0].actions.push((A, undo.clone()));
+        let decisions = e.feed(&[down(CAPS), down(A), up(A), up(CAPS)]);
+        assert_eq!(decisions, vec![Decision::Swallow; 4]);
+        assert_eq!(
+            e.engine.take_replay(),
+            [
+                Replay::Key(ESC, true),
+                Replay::Key(ESC, false),
+                Replay::Action(undo.clone(), true),
+                Replay::Action(undo.clone(), false)
+            ]
+        );
+
+        e.feed(&[down(CAPS), down(A), up(CAPS)]);
+        assert_eq!(
+            e.engine.take_replay()[2],
+            Replay::Action(undo.clone(), true)
+        );
+        assert_eq!(e.handle(&up(A)), Decision::Action(undo));
+        assert!(e.engine.consumed.is_empty());
+    }
+
+    #[test]
+    fn test_action_release_follows_the_press_across_bindings_swap() {
+        let undo = KeyAction::Combo(vec![CTRL, 0x2C]);
+        let actions = |action: &KeyAction| {
+            let keymap = Keymap {
+                actions: vec![(A, action.clone())],
+                ..Default::default()
+            };
+            Bindings::new([], vec![keymap], vec![])
+        };
+        let mut e = Harness {
+            engine: Engine::default(),
+            bindings: actions(&undo),
+        };
+        e.handle(&down(A));
+        e.bindings = actions(&KeyAction::Combo(vec![CTRL, C]));
+        assert_eq!(e.handle(&up(A)), Decision::Action(undo));
+    }
+}
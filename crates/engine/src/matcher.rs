@@ -0,0 +1,154 @@
+use crate::BlockId;
+use profile::{expand_modifiers, modifier_sides};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Trigger combinations compiled into a trie keyed from the last key
+/// backwards, so a lookup only walks the keys that are held instead of every
+/// binding.
+#[derive(Debug, Default)]
+pub struct TriggerMatcher {
+    root: Node,
+    /// Keys that may be held without being part of the trigger
+    ignored: Vec<u16>,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    block: Option<BlockId>,
+    /// Keyed by the key pressed before the ones on the path to this node
+    children: HashMap<u16, Node>,
+}
+
+impl TriggerMatcher {
+    /// A later duplicate of a combination replaces an earlier one. Generic
+    /// modifiers are expanded to both sides, and a combination naming the
+    /// side explicitly wins over one that came from a generic modifier.
+    /// `ignored` keys may stay held around any trigger.
+    pub fn new(triggers: impl IntoIterator<Item = (Vec<u16>, BlockId)>, ignored: Vec<u16>) -> Self {
+        let (generic, exact): (Vec<_>, Vec<_>) = triggers
+            .into_iter()
+            .filter(|(combo, _)| !combo.is_empty())
+            .partition(|(combo, _)| combo.iter().any(|&k| modifier_sides(k).is_some()));
+        let expanded = generic.into_iter().flat_map(|(combo, block)| {
+            expand_modifiers(&combo)
+                .into_iter()
+                .map(move |combo| (combo, block))
+        });
+        let mut root = Node::default();
+        for (combo, block) in expanded.chain(exact) {
+            let node = combo.iter().rev().fold(&mut root, |node, key| {
+                node.children.entry(*key).or_default()
+            });
+            node.block = Some(block);
+        }
+        Self { root, ignored }
+    }
+
+    pub fn ignored(&self) -> &[u16] {
+        &self.ignored
+    }
+
+    /// The longest trigger completed by pressing `key`, given the keys held
+    /// in press order (including `key`). The combination's keys must have
+    /// been pressed in the order written, and anything else held must be an
+    /// ignored key. Ties go to the lower block id.
+    pub fn find(&self, held: &[u16], key: u16) -> Option<BlockId> {
+        let position = held.iter().position(|&k| k == key)?;
+        // Keys pressed after `key` (it is auto-repeating) can't be part of it
+        if held[position + 1..]
+            .iter()
+            .any(|k| !self.ignored.contains(k))
+        {
+            return None;
+        }
+        let start = self.root.children.get(&key)?;
+        let mut best = None;
+        self.search(start, &held[..position], 1, &mut best);
+        best.map(|(_, Reverse(block))| block)
+    }
+
+    // Walk the keys held before the current node's key from the latest one
+    // back. Ignored keys may be skipped; any other key has to continue the
+    // path.
+    fn search(
+        &self,
+        node: &Node,
+        earlier: &[u16],
+        depth: usize,
+        best: &mut Option<(usize, Reverse<BlockId>)>,
+    ) {
+        match earlier.split_last() {
+            None => {
+                if let Some(block) = node.block {
+                    let candidate = (depth, Reverse(block));
+                    if best.is_none_or(|b| candidate > b) {
+                        *best = Some(candidate);
+                    }
+                }
+            }
+            Some((key, before)) => {
+                if let Some(child) = node.children.get(key) {
+                    self.search(child, before, depth + 1, best);
+                }
+                if self.ignored.contains(key) {
+                    self.search(node, before, depth, best);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAPS: u16 = 0x3A;
+    const HENKAN: u16 = 0x79;
+
+    fn matcher(triggers: &[(&[u16], BlockId)]) -> TriggerMatcher {
+        TriggerMatcher::new(
+            triggers.iter().map(|(combo, id)| (combo.to_vec(), *id)),
+            vec![CAPS, HENKAN],
+        )
+    }
+
+    #[test]
+    fn test_status_key_in_combo_is_consumed_or_skipped() {
+        let m = matcher(&[(&[CAPS, 0x1E], 0), (&[0x1E], 1)]);
+        assert_eq!(m.find(&[CAPS, 0x1E], 0x1E), Some(0));
+        assert_eq!(m.find(&[HENKAN, 0x1E], 0x1E), Some(1));
+        assert_eq!(m.find(&[0x1E], 0x1E), Some(1));
+    }
+
+    #[test]
+    fn test_repeat_of_last_key_with_status_key_pressed_later() {
+        let m = matcher(&[(&[0x2A, 0x1E], 0)]);
+        assert_eq!(m.find(&[0x2A, 0x1E, CAPS], 0x1E), Some(0));
+        assert_eq!(m.find(&[0x2A, 0x1E, 0x30], 0x1E), None);
+    }
+
+    #[test]
+    fn test_only_configured_keys_are_ignored() {
+        let m = TriggerMatcher::new([(vec![0x1E], 0)], vec![0x46]);
+        assert_eq!(m.find(&[0x46, 0x1E], 0x1E), Some(0));
+        assert_eq!(m.find(&[CAPS, 0x1E], 0x1E), None);
+    }
+
+    #[test]
+    fn test_generic_modifier_matches_either_side() {
+        let ctrl = profile::key_map::CTRL;
+        let m = matcher(&[(&[ctrl, 0x2E], 0), (&[0xE01D, 0x2F], 1), (&[0x1D, 0x2E], 2)]);
+        assert_eq!(m.find(&[0xE01D, 0x2E], 0x2E), Some(0));
+        // The explicit left side wins regardless of order
+        assert_eq!(m.find(&[0x1D, 0x2E], 0x2E), Some(2));
+        assert_eq!(m.find(&[0x1D, 0x2F], 0x2F), None);
+    }
+
+    #[test]
+    fn test_prefix_without_block_does_not_match() {
+        let m = matcher(&[(&[0x2A, 0x1D, 0x1E], 0)]);
+        assert_eq!(m.find(&[0x1D, 0x1E], 0x1E), None);
+        assert_eq!(m.find(&[0x2A, 0x1D, 0x1E], 0x1E), Some(0));
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{BlockId, TriggerMatcher};
+
+/// Index of a step in a [`SequenceMatcher`]
+pub(crate) type NodeId = usize;
+
+/// Sequence triggers (`Code_G -> Code_G`) compiled into a tree of steps. Each
+/// step is a key combination matched like an ordinary trigger, by a
+/// [`TriggerMatcher`] from the node reached so far to the next one.
+#[derive(Debug)]
+pub(crate) struct SequenceMatcher {
+    /// `ROOT` first
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    block: Option<BlockId>,
+    /// Ids are indices into `nodes`
+    next: TriggerMatcher,
+}
+
+impl SequenceMatcher {
+    /// The node before the first key of any sequence
+    pub(crate) const ROOT: NodeId = 0;
+
+    /// A later duplicate of a sequence replaces an earlier one. A sequence
+    /// that is a prefix of a longer one fires as soon as it is complete, so
+    /// the longer one can never fire.
+    pub(crate) fn new(
+        sequences: impl IntoIterator<Item = (Vec<Vec<u16>>, BlockId)>,
+        ignored: &[u16],
+    ) -> Self {
+        #[derive(Default)]
+        struct Building {
+            block: Option<BlockId>,
+            /// Steps leading out of the node, with the node they lead to
+            steps: Vec<(Vec<u16>, NodeId)>,
+        }
+
+        let mut tree = vec![Building::default()];
+        for (steps, block) in sequences {
+            if steps.is_empty() || steps.iter().any(Vec::is_empty) {
+                continue;
+            }
+            let mut node = Self::ROOT;
+            for step in steps {
+                node = match tree[node].steps.iter().find(|(s, _)| *s == step) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        tree.push(Building::default());
+                        let child = tree.len() - 1;
+                        tree[node].steps.push((step, child));
+                        child
+                    }
+                };
+            }
+            tree[node].block = Some(block);
+        }
+        let nodes = tree
+            .into_iter()
+            .map(|b| Node {
+                block: b.block,
+                next: TriggerMatcher::new(b.steps, ignored.to_vec()),
+            })
+            .collect();
+        Self { nodes }
+    }
+
+    /// The node reached from `node` by pressing `key`, see
+    /// [`TriggerMatcher::find`].
+    pub(crate) fn step(&self, node: NodeId, held: &[u16], key: u16) -> Option<NodeId> {
+        self.nodes.get(node)?.next.find(held, key)
+    }
+
+    /// The block of the sequence ending at `node`
+    pub(crate) fn block(&self, node: NodeId) -> Option<BlockId> {
+        self.nodes.get(node)?.block
+    }
+}
+
+impl Default for SequenceMatcher {
+    fn default() -> Self {
+        Self::new([], &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const G: u16 = 0x22;
+    const SPACE: u16 = 0x39;
+    const F: u16 = 0x21;
+
+    #[test]
+    fn test_steps_share_prefixes() {
+        let m = SequenceMatcher::new(
+            [
+                (vec![vec![SPACE], vec![F], vec![G]], 0),
+                (vec![vec![SPACE], vec![G]], 1),
+            ],
+            &[],
+        );
+        let space = m.step(SequenceMatcher::ROOT, &[SPACE], SPACE).unwrap();
+        assert_eq!(m.block(space), None);
+        let g = m.step(space, &[G], G).unwrap();
+        assert_eq!(m.block(g), Some(1));
+        let f = m.step(space, &[F], F).unwrap();
+        assert_eq!(m.step(f, &[F], F), None);
+        assert_eq!(m.block(m.step(f, &[G], G).unwrap()), Some(0));
+    }
+}
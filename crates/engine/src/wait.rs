@@ -0,0 +1,250 @@
+use crate::KeyEvent;
+use profile::matches_key;
+use std::collections::BTreeSet;
+use std::time::Instant;
+
+/// What a [`KeyWait`] waits for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitFor {
+    /// The last missing key of an alternative goes down
+    Pressed,
+    /// One of the keys of an alternative goes up
+    Released,
+}
+
+/// Where a [`KeyWait`] stands after an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitState {
+    Waiting,
+    /// The index of the alternative that matched
+    Matched(usize),
+    /// A key belonging to none of the alternatives was pressed
+    Aborted,
+    TimedOut,
+}
+
+/// The state of a script's `wait_input`/`wait_released` (and their timed
+/// versions), fed with the key events that follow its start.
+///
+/// Generic modifiers in the alternatives match either side, and an
+/// alternative with a key unknown to the profile (`None`) never matches.
+/// Injected events and auto-repeat of held keys are ignored.
+#[derive(Debug)]
+pub struct KeyWait {
+    alternatives: Vec<Option<Vec<u16>>>,
+    wait_for: WaitFor,
+    deadline: Option<Instant>,
+    held: BTreeSet<u16>,
+}
+
+impl KeyWait {
+    pub fn new(
+        alternatives: Vec<Option<Vec<u16>>>,
+        wait_for: WaitFor,
+        deadline: Option<Instant>,
+    ) -> Self {
+        Self {
+            alternatives,
+            wait_for,
+            deadline,
+            held: BTreeSet::new(),
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Start from the keys `held` now. A released wait matches at once when
+    /// one of its alternatives isn't held to begin with.
+    pub fn start(&mut self, held: BTreeSet<u16>) -> WaitState {
+        self.held = held;
+        match self.wait_for {
+            WaitFor::Pressed => WaitState::Waiting,
+            WaitFor::Released => self.first(|keys, held| !all_held(keys, held)),
+        }
+    }
+
+    /// Start over from the keys `held` now after missing some events. What
+    /// happened in between is only known from the difference: a pressed wait
+    /// matches an alternative that became fully held, a released wait one
+    /// that no longer is. Keys pressed and released in between are lost, so
+    /// they can't abort the wait.
+    pub fn restart(&mut self, held: BTreeSet<u16>) -> WaitState {
+        let before = std::mem::replace(&mut self.held, held);
+        match self.wait_for {
+            WaitFor::Pressed => {
+                self.first(|keys, held| all_held(keys, held) && !all_held(keys, &before))
+            }
+            WaitFor::Released => self.first(|keys, held| !all_held(keys, held)),
+        }
+    }
+
+    pub fn feed(&mut self, event: &KeyEvent) -> WaitState {
+        if self.poll(event.timestamp) == WaitState::TimedOut {
+            return WaitState::TimedOut;
+        }
+        if event.injected {
+            return WaitState::Waiting; // a script's own `Send`s must not abort the wait
+        }
+
+        // Alternatives containing the key
+        let involved: Vec<usize> = self
+            .alternatives
+            .iter()
+            .enumerate()
+            .filter(|(_, keys)| {
+                keys.as_ref()
+                    .is_some_and(|k| k.iter().any(|&k| matches_key(k, event.scancode)))
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if !event.down {
+            self.held.remove(&event.scancode);
+            // A generic modifier stays held while its other side is down
+            if self.wait_for == WaitFor::Released
+                && let Some(&i) = involved
+                    .iter()
+                    .find(|&&i| !all_held(&self.alternatives[i], &self.held))
+            {
+                return WaitState::Matched(i);
+            }
+            return WaitState::Waiting;
+        }
+        if !self.held.insert(event.scancode) {
+            return WaitState::Waiting; // auto-repeat
+        }
+        if involved.is_empty() {
+            return WaitState::Aborted;
+        }
+        if self.wait_for == WaitFor::Pressed
+            && let Some(&i) = involved
+                .iter()
+                .find(|&&i| all_held(&self.alternatives[i], &self.held))
+        {
+            return WaitState::Matched(i);
+        }
+        WaitState::Waiting
+    }
+
+    /// `TimedOut` once `now` is past the deadline, otherwise `Waiting`.
+    pub fn poll(&self, now: Instant) -> WaitState {
+        match self.deadline {
+            Some(deadline) if now >= deadline => WaitState::TimedOut,
+            _ => WaitState::Waiting,
+        }
+    }
+
+    fn first(&self, pred: impl Fn(&Option<Vec<u16>>, &BTreeSet<u16>) -> bool) -> WaitState {
+        self.alternatives
+            .iter()
+            .position(|keys| pred(keys, &self.held))
+            .map_or(WaitState::Waiting, WaitState::Matched)
+    }
+}
+
+/// Whether every key of `keys` is held; generic modifiers count as held when
+/// either side is.
+pub fn all_held(keys: &Option<Vec<u16>>, held: &BTreeSet<u16>) -> bool {
+    keys.as_ref().is_some_and(|keys| {
+        keys.iter()
+            .all(|&k| held.iter().any(|&h| matches_key(k, h)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const A: u16 = 0x1E;
+    const B: u16 = 0x30;
+    const C: u16 = 0x2E;
+    const LSHIFT: u16 = 0x2A;
+    const RSHIFT: u16 = 0x36;
+
+    fn down(scancode: u16) -> KeyEvent {
+        KeyEvent::new(scancode, true)
+    }
+
+    fn up(scancode: u16) -> KeyEvent {
+        KeyEvent::new(scancode, false)
+    }
+
+    // Waits for A or Shift+B
+    fn wait(wait_for: WaitFor, held: &[u16]) -> (KeyWait, WaitState) {
+        let mut wait = KeyWait::new(
+            vec![Some(vec![A]), Some(vec![profile::key_map::SHIFT, B])],
+            wait_for,
+            None,
+        );
+        let state = wait.start(held.iter().copied().collect());
+        (wait, state)
+    }
+
+    #[test]
+    fn test_pressed() {
+        let (mut w, state) = wait(WaitFor::Pressed, &[A]);
+        // Held before the wait started
+        assert_eq!(state, WaitState::Waiting);
+        assert_eq!(w.feed(&down(A)), WaitState::Waiting);
+        assert_eq!(w.feed(&up(A)), WaitState::Waiting);
+        assert_eq!(w.feed(&down(A)), WaitState::Matched(0));
+    }
+
+    #[test]
+    fn test_multiple_alternatives() {
+        let (mut w, _) = wait(WaitFor::Pressed, &[]);
+        assert_eq!(w.feed(&down(RSHIFT)), WaitState::Waiting);
+        assert_eq!(w.feed(&down(B)), WaitState::Matched(1));
+
+        let (mut w, _) = wait(WaitFor::Pressed, &[]);
+        assert_eq!(w.feed(&down(C)), WaitState::Aborted);
+
+        let (mut w, _) = wait(WaitFor::Pressed, &[]);
+        let injected = KeyEvent {
+            injected: true,
+            ..down(C)
+        };
+        assert_eq!(w.feed(&injected), WaitState::Waiting);
+    }
+
+    #[test]
+    fn test_released() {
+        let (_, state) = wait(WaitFor::Released, &[LSHIFT]);
+        assert_eq!(state, WaitState::Matched(0));
+
+        let (mut w, state) = wait(WaitFor::Released, &[A, LSHIFT, RSHIFT, B]);
+        assert_eq!(state, WaitState::Waiting);
+        // The other side of Shift is still down
+        assert_eq!(w.feed(&up(LSHIFT)), WaitState::Waiting);
+        assert_eq!(w.feed(&up(RSHIFT)), WaitState::Matched(1));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(100);
+        let mut w = KeyWait::new(vec![Some(vec![A])], WaitFor::Pressed, Some(deadline));
+        assert_eq!(w.start(BTreeSet::new()), WaitState::Waiting);
+        assert_eq!(w.deadline(), Some(deadline));
+        assert_eq!(w.poll(start), WaitState::Waiting);
+        assert_eq!(w.poll(deadline), WaitState::TimedOut);
+        let late = KeyEvent {
+            timestamp: deadline + Duration::from_millis(1),
+            ..down(A)
+        };
+        assert_eq!(w.feed(&late), WaitState::TimedOut);
+    }
+
+    #[test]
+    fn test_restart() {
+        let (mut w, _) = wait(WaitFor::Pressed, &[]);
+        assert_eq!(w.feed(&down(RSHIFT)), WaitState::Waiting);
+        assert_eq!(w.restart([RSHIFT].into()), WaitState::Waiting);
+        assert_eq!(w.restart([RSHIFT, B].into()), WaitState::Matched(1));
+
+        let (mut w, _) = wait(WaitFor::Released, &[A, LSHIFT, B]);
+        assert_eq!(w.restart([A, B].into()), WaitState::Matched(1));
+    }
+}
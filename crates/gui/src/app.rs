@@ -16,12 +16,18 @@ pub struct PhybkcApp {
     pub current_profile: Option<Profile>,
     pub selected_view: View,
     pub editing_script: Option<(String, String)>, // (Path, Content)
+    pub script_error: Option<dsl::ParseError>,
 
     // Profile Management State
     pub new_profile_name: String,
     pub import_path: String,
     pub new_script_path: String,
     pub last_scancode: Option<u16>,
+
+    // Mappings State
+    /// 0 for the profile's own keys, `i` for `layers[i - 1]`
+    pub mapping_layer: usize,
+    pub new_layer_name: String,
     scancode_slot: Arc<AtomicU16>,
 }
 
@@ -39,10 +45,13 @@ impl PhybkcApp {
             current_profile: None,
             selected_view: View::Profiles,
             editing_script: None,
+            script_error: None,
             new_profile_name: String::new(),
             import_path: String::new(),
             new_script_path: String::new(),
             last_scancode: None,
+            mapping_layer: 0,
+            new_layer_name: String::new(),
             scancode_slot,
         };
         app.load_default_profile();
@@ -76,6 +85,9 @@ impl PhybkcApp {
             keyboard: "Default".to_string(),
             scripts: vec![],
             keys: std::collections::HashMap::new(),
+            ignored_for_matching: None,
+            sequence_timeout_ms: None,
+            layers: Vec::new(),
         };
         if let Some(config) = &mut self.config {
             let _ = std::fs::create_dir_all("profiles");
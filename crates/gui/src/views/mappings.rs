@@ -1,4 +1,5 @@
 use eframe::egui;
+use profile::{KeyMapping, Layer, LayerMode, LayerSwitch, MacroMapping, TapHold, TextMapping};
 
 pub fn mappings_view(ui: &mut egui::Ui, app: &mut crate::app::PhybkcApp) {
     ui.heading("Key Mappings");
@@ -35,15 +36,59 @@ pub fn mappings_view(ui: &mut egui::Ui, app: &mut crate::app::PhybkcApp) {
     };
 
     let mut changed = false;
+    if app.mapping_layer > profile.layers.len() {
+        app.mapping_layer = 0;
+    }
+
+    // Layer selection Section
+    ui.horizontal(|ui| {
+        ui.label("Layer:");
+        let selected_text = match app.mapping_layer {
+            0 => "Base".to_string(),
+            i => profile.layers[i - 1].name.clone(),
+        };
+        egui::ComboBox::from_id_salt("mapping_layer")
+            .selected_text(selected_text)
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut app.mapping_layer, 0, "Base");
+                for (i, layer) in profile.layers.iter().enumerate() {
+                    ui.selectable_value(&mut app.mapping_layer, i + 1, &layer.name);
+                }
+            });
+        if app.mapping_layer > 0 && ui.button("🗑 Remove Layer").clicked() {
+            profile.layers.remove(app.mapping_layer - 1);
+            app.mapping_layer = 0;
+            changed = true;
+        }
+
+        ui.separator();
+        ui.text_edit_singleline(&mut app.new_layer_name);
+        let name = app.new_layer_name.trim().to_string();
+        if ui.button("✚ Add Layer").clicked()
+            && !name.is_empty()
+            && !profile.layers.iter().any(|l| l.name == name)
+        {
+            profile.layers.push(Layer {
+                name,
+                keys: std::collections::HashMap::new(),
+            });
+            app.mapping_layer = profile.layers.len();
+            app.new_layer_name.clear();
+            changed = true;
+        }
+    });
+
+    ui.add_space(10.0);
 
     ui.horizontal(|ui| {
         if ui.button("✚ Add Mapping").clicked() {
+            let keys = layer_keys(&mut profile, app.mapping_layer);
             // Find a unique key that doesn't exist yet
             let mut new_sc = 0u16;
             loop {
                 let key = format!("0x{:02X}", new_sc);
-                if let std::collections::hash_map::Entry::Vacant(e) = profile.keys.entry(key) {
-                    e.insert("None".to_string());
+                if let std::collections::hash_map::Entry::Vacant(e) = keys.entry(key) {
+                    e.insert(KeyMapping::from("None"));
                     break;
                 }
                 new_sc += 1;
@@ -67,13 +112,16 @@ pub fn mappings_view(ui: &mut egui::Ui, app: &mut crate::app::PhybkcApp) {
 
     ui.add_space(10.0);
 
+    let layer_names: Vec<String> = profile.layers.iter().map(|l| l.name.clone()).collect();
+    let keys = layer_keys(&mut profile, app.mapping_layer);
     egui::ScrollArea::vertical().show(ui, |ui| {
         egui::Grid::new("mapping_grid")
-            .num_columns(3)
+            .num_columns(4)
             .spacing([20.0, 10.0])
             .striped(true)
             .show(ui, |ui| {
                 ui.label("Physical Key (Hex)");
+                ui.label("Kind");
                 ui.label("Mapped To (Virtual Name)");
                 ui.label("Action");
                 ui.end_row();
@@ -81,31 +129,32 @@ pub fn mappings_view(ui: &mut egui::Ui, app: &mut crate::app::PhybkcApp) {
                 let mut keys_to_remove = Vec::new();
                 let mut keys_to_update = Vec::new();
 
-                for (sc, name) in &profile.keys {
+                for (sc, mapping) in keys.iter() {
                     let mut sc_edit = sc.clone();
-                    let mut name_edit = name.clone();
+                    let mut mapping_edit = mapping.clone();
 
                     ui.text_edit_singleline(&mut sc_edit);
-                    ui.text_edit_singleline(&mut name_edit);
+                    mapping_kind_combo(ui, sc, &mut mapping_edit, &layer_names);
+                    mapping_editor(ui, sc, &mut mapping_edit, &layer_names);
 
                     if ui.button("🗑").clicked() {
                         keys_to_remove.push(sc.clone());
                     }
                     ui.end_row();
 
-                    if sc_edit != *sc || name_edit != *name {
-                        keys_to_update.push((sc.clone(), sc_edit, name_edit));
+                    if sc_edit != *sc || mapping_edit != *mapping {
+                        keys_to_update.push((sc.clone(), sc_edit, mapping_edit));
                     }
                 }
 
                 for sc in keys_to_remove {
-                    profile.keys.remove(&sc);
+                    keys.remove(&sc);
                     changed = true;
                 }
 
-                for (old_sc, new_sc, new_name) in keys_to_update {
-                    profile.keys.remove(&old_sc);
-                    profile.keys.insert(new_sc, new_name);
+                for (old_sc, new_sc, new_mapping) in keys_to_update {
+                    keys.remove(&old_sc);
+                    keys.insert(new_sc, new_mapping);
                     changed = true;
                 }
             });
@@ -115,3 +164,125 @@ pub fn mappings_view(ui: &mut egui::Ui, app: &mut crate::app::PhybkcApp) {
         app.current_profile = Some(profile);
     }
 }
+
+/// The `keys` table of the profile (layer 0) or of one of its layers
+fn layer_keys(
+    profile: &mut profile::Profile,
+    layer: usize,
+) -> &mut std::collections::HashMap<String, KeyMapping> {
+    match layer {
+        0 => &mut profile.keys,
+        i => &mut profile.layers[i - 1].keys,
+    }
+}
+
+/// Switch a mapping between a plain key, a tap-hold key, a layer key, a
+/// string and a macro, keeping what carries over.
+fn mapping_kind_combo(
+    ui: &mut egui::Ui,
+    sc: &str,
+    mapping: &mut KeyMapping,
+    layer_names: &[String],
+) {
+    let kind = match mapping {
+        KeyMapping::Key(_) => "Key",
+        KeyMapping::TapHold(_) => "Tap-Hold",
+        KeyMapping::Layer(_) => "Layer",
+        KeyMapping::Text(_) => "Text",
+        KeyMapping::Macro(_) => "Macro",
+    };
+    let mut new_kind = kind;
+    egui::ComboBox::from_id_salt(("mapping_kind", sc))
+        .selected_text(kind)
+        .show_ui(ui, |ui| {
+            for k in ["Key", "Tap-Hold", "Layer", "Text", "Macro"] {
+                ui.selectable_value(&mut new_kind, k, k);
+            }
+        });
+    if new_kind == kind {
+        return;
+    }
+    let name = match mapping {
+        KeyMapping::Key(name) => name.clone(),
+        KeyMapping::TapHold(tap_hold) => tap_hold.tap.clone(),
+        KeyMapping::Text(text) => text.text.clone(),
+        KeyMapping::Macro(m) => m.name.clone(),
+        KeyMapping::Layer(_) => "None".to_string(),
+    };
+    *mapping = match new_kind {
+        "Tap-Hold" => KeyMapping::TapHold(TapHold {
+            tap: name,
+            hold: "Ctrl".to_string(),
+            tapping_term_ms: None,
+            permissive_hold: false,
+        }),
+        "Layer" => KeyMapping::Layer(LayerSwitch {
+            layer: layer_names.first().cloned().unwrap_or_default(),
+            mode: LayerMode::Momentary,
+        }),
+        "Text" => KeyMapping::Text(TextMapping { text: name }),
+        "Macro" => KeyMapping::Macro(MacroMapping {
+            name,
+            args: Vec::new(),
+        }),
+        _ => KeyMapping::Key(name),
+    };
+}
+
+fn mapping_editor(ui: &mut egui::Ui, sc: &str, mapping: &mut KeyMapping, layer_names: &[String]) {
+    match mapping {
+        KeyMapping::Key(name) => {
+            ui.text_edit_singleline(name)
+                .on_hover_text("A key name, or `Ctrl+Z` for a combo");
+        }
+        KeyMapping::Text(text) => {
+            ui.text_edit_singleline(&mut text.text);
+        }
+        KeyMapping::Macro(m) => {
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(&mut m.name).desired_width(80.0));
+                ui.label("Args");
+                // One field, arguments separated by commas
+                let joined = m.args.join(", ");
+                let mut args_edit = joined.clone();
+                ui.add(egui::TextEdit::singleline(&mut args_edit).desired_width(120.0));
+                if args_edit != joined {
+                    m.args = if args_edit.trim().is_empty() {
+                        Vec::new()
+                    } else {
+                        args_edit
+                            .split(',')
+                            .map(|arg| arg.trim().to_string())
+                            .collect()
+                    };
+                }
+            });
+        }
+        KeyMapping::TapHold(tap_hold) => {
+            ui.horizontal(|ui| {
+                ui.label("Tap");
+                ui.add(egui::TextEdit::singleline(&mut tap_hold.tap).desired_width(80.0));
+                ui.label("Hold");
+                ui.add(egui::TextEdit::singleline(&mut tap_hold.hold).desired_width(80.0));
+            });
+        }
+        KeyMapping::Layer(switch) => {
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_id_salt(("mapping_layer_name", sc))
+                    .selected_text(switch.layer.as_str())
+                    .show_ui(ui, |ui| {
+                        for name in layer_names {
+                            ui.selectable_value(&mut switch.layer, name.clone(), name);
+                        }
+                    });
+                egui::ComboBox::from_id_salt(("mapping_layer_mode", sc))
+                    .selected_text(format!("{:?}", switch.mode))
+                    .show_ui(ui, |ui| {
+                        for mode in [LayerMode::Momentary, LayerMode::Toggle, LayerMode::OneShot] {
+                            ui.selectable_value(&mut switch.mode, mode, format!("{:?}", mode));
+                        }
+                    });
+            });
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use eframe::egui;
+use std::path::Path;
 
 pub fn scripts_view(ui: &mut egui::Ui, app: &mut crate::app::PhybkcApp) {
     ui.heading("Active Scripts");
@@ -27,11 +28,26 @@ pub fn scripts_view(ui: &mut egui::Ui, app: &mut crate::app::PhybkcApp) {
                     if std::fs::write(&script_data.0, &script_data.1).is_ok() {
                         println!("Saved script: {}", script_data.0);
                     }
+                    app.script_error =
+                        dsl::load_source(&script_data.1, Path::new(&script_data.0)).err();
+                }
+                if ui.button("Check").clicked() {
+                    app.script_error =
+                        dsl::load_source(&script_data.1, Path::new(&script_data.0)).err();
                 }
             });
         });
         ui.add_space(10.0);
 
+        if let Some(err) = &app.script_error {
+            ui.label(
+                egui::RichText::new(err.to_string())
+                    .monospace()
+                    .color(egui::Color32::from_rgb(255, 100, 100)),
+            );
+            ui.add_space(10.0);
+        }
+
         egui::ScrollArea::vertical().show(ui, |ui| {
             ui.add_sized(
                 ui.available_size(),
@@ -43,6 +59,7 @@ pub fn scripts_view(ui: &mut egui::Ui, app: &mut crate::app::PhybkcApp) {
 
         if close_editor {
             app.editing_script = None;
+            app.script_error = None;
         }
         return;
     }
@@ -62,6 +79,7 @@ pub fn scripts_view(ui: &mut egui::Ui, app: &mut crate::app::PhybkcApp) {
                                 && let Ok(content) = std::fs::read_to_string(script_path)
                             {
                                 app.editing_script = Some((script_path.clone(), content));
+                                app.script_error = None;
                             }
                         });
                     });
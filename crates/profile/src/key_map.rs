@@ -1,6 +1,13 @@
 use std::collections::HashMap;
 use std::sync::OnceLock;
 
+/// Pseudo scan codes for modifiers that match either side. They never come
+/// from the keyboard; see [`modifier_sides`].
+pub const CTRL: u16 = 0xF01D;
+pub const SHIFT: u16 = 0xF02A;
+pub const ALT: u16 = 0xF038;
+pub const WIN: u16 = 0xF05B;
+
 static NAME_TO_SCANCODE: OnceLock<HashMap<&'static str, u16>> = OnceLock::new();
 static SCANCODE_TO_NAME: OnceLock<HashMap<u16, &'static str>> = OnceLock::new();
 
@@ -76,7 +83,6 @@ fn init_maps() -> (HashMap<&'static str, u16>, HashMap<u16, &'static str>) {
     map!("Backspace", 0x0E);
     map!("Tab", 0x0F);
     map!("Enter", 0x1C);
-    map!("Ctrl", 0x1D); // Left Ctrl
     map!("LeftCtrl", 0x1D);
     map!("SemiColon", 0x27);
     map!("Quote", 0x28);
@@ -84,14 +90,12 @@ fn init_maps() -> (HashMap<&'static str, u16>, HashMap<u16, &'static str>) {
     map!("LeftBracket", 0x1A);
     map!("RightBracket", 0x1B);
     map!("LeftShift", 0x2A);
-    map!("Shift", 0x2A); // Default to Left Shift
     map!("BackSlash", 0x2B);
     map!("Comma", 0x33);
     map!("Period", 0x34);
     map!("Slash", 0x35);
     map!("RightShift", 0x36);
     map!("LeftAlt", 0x38);
-    map!("Alt", 0x38); // Default to Left Alt
     map!("Space", 0x39);
     map!("CapsLock", 0x3A);
 
@@ -135,6 +139,12 @@ fn init_maps() -> (HashMap<&'static str, u16>, HashMap<u16, &'static str>) {
     map!("NumEnter", 0xE01C);
     map!("NumSlash", 0xE035);
 
+    // Either side
+    map!("Ctrl", CTRL);
+    map!("Shift", SHIFT);
+    map!("Alt", ALT);
+    map!("Win", WIN);
+
     (n2s, s2n)
 }
 
@@ -161,3 +171,78 @@ pub fn get_scancode(name: &str) -> Option<u16> {
 pub fn get_name(scancode: u16) -> Option<&'static str> {
     get_maps().1.get(&scancode).copied()
 }
+
+/// The left and right scan codes of a generic modifier, `None` for any other
+/// key.
+pub fn modifier_sides(scancode: u16) -> Option<[u16; 2]> {
+    match scancode {
+        CTRL => Some([0x1D, 0xE01D]),
+        SHIFT => Some([0x2A, 0x36]),
+        ALT => Some([0x38, 0xE038]),
+        WIN => Some([0xE05B, 0xE05C]),
+        _ => None,
+    }
+}
+
+/// Whether pressing `actual` counts as pressing `key`: the same key, or
+/// either side of a generic modifier.
+pub fn matches_key(key: u16, actual: u16) -> bool {
+    key == actual || modifier_sides(key).is_some_and(|sides| sides.contains(&actual))
+}
+
+/// The key to emit for `scancode`: the left side of a generic modifier,
+/// otherwise the key itself.
+pub fn sendable_scancode(scancode: u16) -> u16 {
+    modifier_sides(scancode).map_or(scancode, |[left, _]| left)
+}
+
+/// Every combination of physical keys `keys` can stand for, with each
+/// generic modifier replaced by one of its sides.
+pub fn expand_modifiers(keys: &[u16]) -> Vec<Vec<u16>> {
+    keys.iter().fold(vec![Vec::new()], |combos, &key| {
+        let sides = modifier_sides(key);
+        let choices = sides
+            .as_ref()
+            .map_or(std::slice::from_ref(&key), |s| s.as_slice());
+        combos
+            .iter()
+            .flat_map(|combo| {
+                choices.iter().map(move |&choice| {
+                    let mut combo = combo.clone();
+                    combo.push(choice);
+                    combo
+                })
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generic_modifiers() {
+        assert_eq!(get_scancode("Ctrl"), Some(CTRL));
+        assert_eq!(get_scancode("LeftCtrl"), Some(0x1D));
+        assert_eq!(get_name(0x2A), Some("LeftShift"));
+        assert!(matches_key(CTRL, 0xE01D));
+        assert!(!matches_key(0x1D, 0xE01D));
+        assert_eq!(sendable_scancode(WIN), 0xE05B);
+        assert_eq!(sendable_scancode(0x1E), 0x1E);
+    }
+
+    #[test]
+    fn test_expand_modifiers() {
+        assert_eq!(
+            expand_modifiers(&[CTRL, SHIFT, 0x2E]),
+            [
+                [0x1D, 0x2A, 0x2E],
+                [0x1D, 0x36, 0x2E],
+                [0xE01D, 0x2A, 0x2E],
+                [0xE01D, 0x36, 0x2E],
+            ]
+        );
+        assert_eq!(expand_modifiers(&[0x1E]), [[0x1E]]);
+    }
+}
@@ -2,9 +2,17 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use std::time::Duration;
 
 pub mod key_map;
-pub use key_map::{get_name, get_scancode};
+mod remap;
+pub use key_map::{
+    expand_modifiers, get_name, get_scancode, matches_key, modifier_sides, sendable_scancode,
+};
+pub use remap::{
+    DualRole, KeyAction, KeyNames, Keymap, LayerKey, RemapTable, SCANCODE_SLOTS, index_scancode,
+    scancode_index,
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
@@ -61,10 +69,150 @@ pub struct Profile {
     pub name: String,
     pub keyboard: String,
     pub scripts: Vec<String>,
-    pub keys: HashMap<String, String>,
+    pub keys: HashMap<String, KeyMapping>,
+    /// Keys that may stay held without breaking a trigger, such as lock keys.
+    /// Falls back to the default for `keyboard` when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignored_for_matching: Option<Vec<String>>,
+    /// How long a sequence trigger waits for its next key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence_timeout_ms: Option<u64>,
+    /// Key maps over `keys`, each above the ones before it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<Layer>,
+}
+
+/// A named set of mappings that is switched on by a [`LayerSwitch`] key.
+/// Keys it doesn't map fall through to the active layer below it, and
+/// finally to the profile's own `keys`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Layer {
+    pub name: String,
+    pub keys: HashMap<String, KeyMapping>,
+}
+
+const DEFAULT_SEQUENCE_TIMEOUT_MS: u64 = 1000;
+
+/// What a physical key in `keys` is turned into. A plain string is a key
+/// name (`"A"`), as in older profiles, or keys joined by `+` that are pressed
+/// together (`"Ctrl+Z"`).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum KeyMapping {
+    Key(String),
+    TapHold(TapHold),
+    Layer(LayerSwitch),
+    Text(TextMapping),
+    Macro(MacroMapping),
+}
+
+impl From<&str> for KeyMapping {
+    fn from(name: &str) -> Self {
+        KeyMapping::Key(name.to_string())
+    }
+}
+
+/// A dual-role key: `{"tap": "Escape", "hold": "LeftCtrl"}`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TapHold {
+    /// Sent when the key is released within the tapping term
+    pub tap: String,
+    /// Held down while the key is, once the tapping term has passed
+    pub hold: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tapping_term_ms: Option<u64>,
+    /// Pressing another key before the tapping term is over means `hold`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub permissive_hold: bool,
+}
+
+const DEFAULT_TAPPING_TERM_MS: u64 = 200;
+
+/// Types a string when the key is pressed: `{"text": "→"}`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TextMapping {
+    pub text: String,
+}
+
+/// Runs a macro of the profile's scripts when the key is pressed:
+/// `{"macro": "OpenIn", "args": ["code", "notes.md"]}`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MacroMapping {
+    #[serde(rename = "macro")]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+}
+
+/// A key that switches a layer on: `{"layer": "nav", "mode": "toggle"}`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct LayerSwitch {
+    /// Name of one of the profile's `layers`
+    pub layer: String,
+    #[serde(default, skip_serializing_if = "LayerMode::is_momentary")]
+    pub mode: LayerMode,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerMode {
+    /// Active while the key is held
+    #[default]
+    Momentary,
+    /// Each press switches the layer on or off
+    Toggle,
+    /// Active for the next key pressed
+    OneShot,
+}
+
+impl LayerMode {
+    fn is_momentary(&self) -> bool {
+        *self == LayerMode::Momentary
+    }
+}
+
+impl TapHold {
+    pub fn tapping_term(&self) -> Duration {
+        Duration::from_millis(self.tapping_term_ms.unwrap_or(DEFAULT_TAPPING_TERM_MS))
+    }
+}
+
+/// Caps Lock, Hankaku/Zenkaku, Muhenkan, Henkan and Hiragana
+const JIS_IGNORED_FOR_MATCHING: [u16; 5] = [0x3A, 0x29, 0x7B, 0x79, 0x70];
+/// Caps Lock
+const US_IGNORED_FOR_MATCHING: [u16; 1] = [0x3A];
+
+/// The keys ignored for matching on a `keyboard` type when the profile does
+/// not list its own. Anything other than `"JIS"` gets the US set.
+pub fn default_ignored_for_matching(keyboard: &str) -> &'static [u16] {
+    match keyboard {
+        "JIS" => &JIS_IGNORED_FOR_MATCHING,
+        _ => &US_IGNORED_FOR_MATCHING,
+    }
 }
 
 impl Profile {
+    pub fn sequence_timeout(&self) -> Duration {
+        Duration::from_millis(
+            self.sequence_timeout_ms
+                .unwrap_or(DEFAULT_SEQUENCE_TIMEOUT_MS),
+        )
+    }
+
+    /// Scan codes of `ignored_for_matching`, or the keyboard's default.
+    /// Generic modifiers stand for both sides and unknown key names are
+    /// skipped.
+    pub fn ignored_scancodes(&self) -> Vec<u16> {
+        match &self.ignored_for_matching {
+            Some(keys) => keys
+                .iter()
+                .filter_map(|k| get_scancode(k))
+                .flat_map(|sc| modifier_sides(sc).map_or(vec![sc], Vec::from))
+                .collect(),
+            None => default_ignored_for_matching(&self.keyboard).to_vec(),
+        }
+    }
+
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let content = fs::read_to_string(path)?;
         let profile: Profile = serde_json::from_str(&content)?;
@@ -119,6 +267,127 @@ default = "qwerty"
         let profile: Profile = serde_json::from_str(json_content).unwrap();
         assert_eq!(profile.name, "profileA");
         assert_eq!(profile.keyboard, "JIS");
-        assert_eq!(profile.keys.get("0x1E").unwrap(), "A");
+        assert_eq!(profile.keys["0x1E"], KeyMapping::from("A"));
+        assert_eq!(profile.ignored_scancodes(), JIS_IGNORED_FOR_MATCHING);
+        assert_eq!(profile.sequence_timeout(), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_tap_hold_mapping() {
+        let json_content = r#"
+{
+    "name": "profileA",
+    "keyboard": "JIS",
+    "scripts": [],
+    "keys": {
+        "0x1E": "A",
+        "0x3A": { "tap": "Escape", "hold": "LeftCtrl", "permissive_hold": true }
+    }
+}
+"#;
+        let profile: Profile = serde_json::from_str(json_content).unwrap();
+        let KeyMapping::TapHold(tap_hold) = &profile.keys["0x3A"] else {
+            panic!(
+                "Expected a tap-hold mapping, got {:?}",
+                profile.keys["0x3A"]
+            );
+        };
+        assert_eq!(tap_hold.tapping_term(), Duration::from_millis(200));
+        assert!(tap_hold.permissive_hold);
+        let saved = serde_json::to_string(&profile).unwrap();
+        assert!(saved.contains(r#""0x1E":"A""#));
+        assert!(!saved.contains("tapping_term_ms"));
+    }
+
+    #[test]
+    fn test_layers() {
+        let json_content = r#"
+{
+    "name": "profileA",
+    "keyboard": "US",
+    "scripts": [],
+    "keys": {
+        "0x39": { "layer": "nav" },
+        "0x3A": { "layer": "nav", "mode": "one_shot" }
+    },
+    "layers": [
+        { "name": "nav", "keys": { "0x23": "ArrowLeft", "0x24": "ArrowDown" } }
+    ]
+}
+"#;
+        let profile: Profile = serde_json::from_str(json_content).unwrap();
+        assert_eq!(
+            profile.layers[0].keys["0x23"],
+            KeyMapping::from("ArrowLeft")
+        );
+        assert_eq!(
+            profile.keys["0x39"],
+            KeyMapping::Layer(LayerSwitch {
+                layer: "nav".to_string(),
+                mode: LayerMode::Momentary,
+            })
+        );
+        let KeyMapping::Layer(switch) = &profile.keys["0x3A"] else {
+            panic!("Expected a layer key, got {:?}", profile.keys["0x3A"]);
+        };
+        assert_eq!(switch.mode, LayerMode::OneShot);
+        let saved = serde_json::to_string(&profile).unwrap();
+        assert!(saved.contains(r#""0x39":{"layer":"nav"}"#));
+    }
+
+    #[test]
+    fn test_text_and_macro_mappings() {
+        let json_content = r#"
+{
+    "name": "profileA",
+    "keyboard": "US",
+    "scripts": [],
+    "keys": {
+        "0x3B": "Ctrl+Z",
+        "0x3C": { "text": "→" },
+        "0x3D": { "macro": "OpenIn", "args": ["code", "notes.md"] },
+        "0x3E": { "macro": "Lock" }
+    }
+}
+"#;
+        let profile: Profile = serde_json::from_str(json_content).unwrap();
+        assert_eq!(profile.keys["0x3B"], KeyMapping::from("Ctrl+Z"));
+        assert_eq!(
+            profile.keys["0x3C"],
+            KeyMapping::Text(TextMapping {
+                text: "→".to_string()
+            })
+        );
+        assert_eq!(
+            profile.keys["0x3D"],
+            KeyMapping::Macro(MacroMapping {
+                name: "OpenIn".to_string(),
+                args: vec!["code".to_string(), "notes.md".to_string()],
+            })
+        );
+        let saved = serde_json::to_string(&profile).unwrap();
+        assert!(saved.contains(r#""0x3E":{"macro":"Lock"}"#));
+    }
+
+    #[test]
+    fn test_ignored_for_matching() {
+        let json_content = r#"
+{
+    "name": "profileUS",
+    "keyboard": "US",
+    "scripts": [],
+    "keys": {},
+    "ignored_for_matching": ["CapsLock", "0x46"]
+}
+"#;
+        let mut profile: Profile = serde_json::from_str(json_content).unwrap();
+        assert_eq!(profile.ignored_scancodes(), [0x3A, 0x46]);
+        profile.ignored_for_matching = None;
+        assert_eq!(profile.ignored_scancodes(), US_IGNORED_FOR_MATCHING);
+        assert!(
+            !serde_json::to_string(&profile)
+                .unwrap()
+                .contains("ignored_for_matching")
+        );
     }
 }
@@ -0,0 +1,433 @@
+use crate::{KeyMapping, LayerMode, Profile, get_scancode, sendable_scancode};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Number of distinct scan codes: 256 plain ones followed by 256 E0-extended
+/// ones.
+pub const SCANCODE_SLOTS: usize = 512;
+
+/// Position of a scan code in a table of [`SCANCODE_SLOTS`] entries, `None`
+/// for codes that are neither plain nor E0-extended.
+pub fn scancode_index(scancode: u16) -> Option<usize> {
+    match scancode & 0xFF00 {
+        0 => Some(scancode as usize),
+        0xE000 => Some(0x100 | (scancode & 0xFF) as usize),
+        _ => None,
+    }
+}
+
+/// Inverse of [`scancode_index`].
+pub fn index_scancode(index: usize) -> u16 {
+    if index < 0x100 {
+        index as u16
+    } else {
+        0xE000 | (index & 0xFF) as u16
+    }
+}
+
+/// A profile's `keys` table compiled into a scan code -> scan code array, so
+/// looking up a remap is an index with no allocation.
+#[derive(Clone, PartialEq, Eq)]
+pub struct RemapTable(Box<[u16; SCANCODE_SLOTS]>);
+
+impl RemapTable {
+    /// The key `scancode` is remapped to, if any.
+    pub fn get(&self, scancode: u16) -> Option<u16> {
+        let target = self.0[scancode_index(scancode)?];
+        (target != 0).then_some(target)
+    }
+
+    /// Remap `from` to `to`. Codes outside the table are ignored.
+    pub fn insert(&mut self, from: u16, to: u16) {
+        if let Some(index) = scancode_index(from) {
+            self.0[index] = to;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.0
+            .iter()
+            .enumerate()
+            .filter(|(_, to)| **to != 0)
+            .map(|(index, to)| (index_scancode(index), *to))
+    }
+}
+
+impl Default for RemapTable {
+    fn default() -> Self {
+        Self(Box::new([0; SCANCODE_SLOTS]))
+    }
+}
+
+impl std::fmt::Debug for RemapTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl FromIterator<(u16, u16)> for RemapTable {
+    fn from_iter<I: IntoIterator<Item = (u16, u16)>>(iter: I) -> Self {
+        let mut table = Self::default();
+        for (from, to) in iter {
+            table.insert(from, to);
+        }
+        table
+    }
+}
+
+/// Key names (`Code_E`) resolved through a profile: the physical key the
+/// profile's `keys` table maps to the name, or the fixed scan code of the
+/// name when no key is mapped to it.
+#[derive(Debug, Clone, Default)]
+pub struct KeyNames(HashMap<String, u16>);
+
+impl KeyNames {
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.0.get(name).copied().or_else(|| get_scancode(name))
+    }
+}
+
+/// A tap-hold entry of `keys` with its keys resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DualRole {
+    pub tap: u16,
+    pub hold: u16,
+    pub tapping_term: Duration,
+    pub permissive_hold: bool,
+}
+
+/// A layer key entry of `keys` with its layer resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerKey {
+    /// Index into [`Profile::keymaps`]
+    pub layer: usize,
+    pub mode: LayerMode,
+}
+
+/// What a key mapped to something other than one key does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyAction {
+    /// Pressed in order when the key goes down, released in reverse when it
+    /// goes up
+    Combo(Vec<u16>),
+    /// Typed when the key goes down
+    Text(String),
+    /// Run when the key goes down
+    Macro { name: String, args: Vec<String> },
+}
+
+/// One `keys` table, of the profile or of a layer, compiled for lookup by
+/// scan code.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Keymap {
+    /// The plain key entries (`"0x1E": "A"`)
+    pub remaps: RemapTable,
+    /// Sorted by scan code
+    pub dual_roles: Vec<(u16, DualRole)>,
+    /// Sorted by scan code
+    pub layer_keys: Vec<(u16, LayerKey)>,
+    /// Sorted by scan code
+    pub actions: Vec<(u16, KeyAction)>,
+}
+
+impl Keymap {
+    pub fn dual_role(&self, scancode: u16) -> Option<DualRole> {
+        let i = self
+            .dual_roles
+            .binary_search_by_key(&scancode, |(sc, _)| *sc)
+            .ok()?;
+        Some(self.dual_roles[i].1)
+    }
+
+    pub fn layer_key(&self, scancode: u16) -> Option<LayerKey> {
+        let i = self
+            .layer_keys
+            .binary_search_by_key(&scancode, |(sc, _)| *sc)
+            .ok()?;
+        Some(self.layer_keys[i].1)
+    }
+
+    /// Position of the action of `scancode` in `actions`
+    pub fn action_index(&self, scancode: u16) -> Option<usize> {
+        self.actions
+            .binary_search_by_key(&scancode, |(sc, _)| *sc)
+            .ok()
+    }
+
+    /// Whether the table has an entry for `scancode`; a layer without one
+    /// lets the key fall through.
+    pub fn maps(&self, scancode: u16) -> bool {
+        self.remaps.get(scancode).is_some()
+            || self.dual_role(scancode).is_some()
+            || self.layer_key(scancode).is_some()
+            || self.action_index(scancode).is_some()
+    }
+}
+
+impl From<RemapTable> for Keymap {
+    fn from(remaps: RemapTable) -> Self {
+        Self {
+            remaps,
+            ..Default::default()
+        }
+    }
+}
+
+impl Profile {
+    /// The profile's `keys` followed by those of each of its `layers`. A
+    /// generic modifier target sends its left side. Entries whose keys or
+    /// layer are unknown are skipped.
+    pub fn keymaps(&self) -> Vec<Keymap> {
+        std::iter::once(&self.keys)
+            .chain(self.layers.iter().map(|layer| &layer.keys))
+            .map(|keys| self.compile(keys))
+            .collect()
+    }
+
+    fn compile(&self, keys: &HashMap<String, KeyMapping>) -> Keymap {
+        let mut keymap = Keymap::default();
+        for (from, to) in keys {
+            let Some(from) = get_scancode(from) else {
+                continue;
+            };
+            let target = |name: &str| get_scancode(name).map(sendable_scancode);
+            match to {
+                KeyMapping::Key(to) => {
+                    if let Some(to) = target(to) {
+                        keymap.remaps.insert(from, to);
+                    } else if let Some(keys) = to
+                        .split('+')
+                        .map(|name| target(name.trim()))
+                        .collect::<Option<Vec<u16>>>()
+                        .filter(|keys| keys.len() > 1)
+                    {
+                        keymap.actions.push((from, KeyAction::Combo(keys)));
+                    }
+                }
+                KeyMapping::Text(text) => {
+                    keymap
+                        .actions
+                        .push((from, KeyAction::Text(text.text.clone())));
+                }
+                KeyMapping::Macro(m) => {
+                    let action = KeyAction::Macro {
+                        name: m.name.clone(),
+                        args: m.args.clone(),
+                    };
+                    keymap.actions.push((from, action));
+                }
+                KeyMapping::TapHold(tap_hold) => {
+                    if let (Some(tap), Some(hold)) = (target(&tap_hold.tap), target(&tap_hold.hold))
+                    {
+                        let role = DualRole {
+                            tap,
+                            hold,
+                            tapping_term: tap_hold.tapping_term(),
+                            permissive_hold: tap_hold.permissive_hold,
+                        };
+                        keymap.dual_roles.push((from, role));
+                    }
+                }
+                KeyMapping::Layer(switch) => {
+                    if let Some(i) = self.layers.iter().position(|l| l.name == switch.layer) {
+                        let layer = LayerKey {
+                            layer: i + 1,
+                            mode: switch.mode,
+                        };
+                        keymap.layer_keys.push((from, layer));
+                    }
+                }
+            }
+        }
+        keymap.dual_roles.sort_unstable_by_key(|(from, _)| *from);
+        keymap.layer_keys.sort_unstable_by_key(|(from, _)| *from);
+        keymap.actions.sort_unstable_by_key(|(from, _)| *from);
+        keymap
+    }
+
+    /// The reverse of the `keys` table. When several keys map to the same
+    /// name, the lowest scan code wins.
+    pub fn key_names(&self) -> KeyNames {
+        let mut names: HashMap<String, u16> = HashMap::new();
+        for (from, to) in &self.keys {
+            let (Some(from), KeyMapping::Key(to)) = (get_scancode(from), to) else {
+                continue;
+            };
+            names
+                .entry(to.clone())
+                .and_modify(|sc| *sc = (*sc).min(from))
+                .or_insert(from);
+        }
+        KeyNames(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remap_table_from_profile() {
+        let profile = Profile {
+            name: "test".to_string(),
+            keyboard: "JIS".to_string(),
+            scripts: vec![],
+            keys: [
+                ("0x1E".to_string(), "B".into()),
+                ("0xE01D".to_string(), "0x3A".into()),
+                ("0x30".to_string(), "NoSuchKey".into()),
+                (
+                    "0x3A".to_string(),
+                    KeyMapping::TapHold(crate::TapHold {
+                        tap: "Escape".to_string(),
+                        hold: "Ctrl".to_string(),
+                        tapping_term_ms: Some(150),
+                        permissive_hold: false,
+                    }),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            ignored_for_matching: None,
+            sequence_timeout_ms: None,
+            layers: vec![],
+        };
+        let keymaps = profile.keymaps();
+        assert_eq!(keymaps.len(), 1);
+        let table = &keymaps[0].remaps;
+        assert_eq!(table.get(0x1E), Some(0x30));
+        assert_eq!(table.get(0xE01D), Some(0x3A));
+        assert_eq!(table.get(0x1D), None);
+        assert_eq!(table.get(0x30), None);
+        assert_eq!(table.iter().count(), 2);
+        assert_eq!(
+            keymaps[0].dual_roles,
+            [(
+                0x3A,
+                DualRole {
+                    tap: 0x01,
+                    hold: 0x1D,
+                    tapping_term: Duration::from_millis(150),
+                    permissive_hold: false,
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn test_key_names_reverse_the_profile() {
+        let profile = Profile {
+            name: "dvorak".to_string(),
+            keyboard: "US".to_string(),
+            scripts: vec![],
+            keys: [
+                ("0x20".to_string(), "E".into()),
+                ("0x12".to_string(), "Period".into()),
+                ("0x34".to_string(), "E".into()),
+            ]
+            .into_iter()
+            .collect(),
+            ignored_for_matching: None,
+            sequence_timeout_ms: None,
+            layers: vec![],
+        };
+        let names = profile.key_names();
+        assert_eq!(names.get("E"), Some(0x20));
+        assert_eq!(names.get("Period"), Some(0x12));
+        // Not mapped by the profile: the fixed table
+        assert_eq!(names.get("A"), Some(0x1E));
+        assert_eq!(names.get("NoSuchKey"), None);
+    }
+
+    #[test]
+    fn test_layer_keymaps() {
+        let profile = Profile {
+            name: "layers".to_string(),
+            keyboard: "US".to_string(),
+            scripts: vec![],
+            keys: [
+                (
+                    "0x39".to_string(),
+                    KeyMapping::Layer(crate::LayerSwitch {
+                        layer: "nav".to_string(),
+                        mode: LayerMode::Momentary,
+                    }),
+                ),
+                (
+                    "0x3A".to_string(),
+                    KeyMapping::Layer(crate::LayerSwitch {
+                        layer: "missing".to_string(),
+                        mode: LayerMode::Toggle,
+                    }),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            ignored_for_matching: None,
+            sequence_timeout_ms: None,
+            layers: vec![crate::Layer {
+                name: "nav".to_string(),
+                keys: [("0x23".to_string(), "ArrowLeft".into())]
+                    .into_iter()
+                    .collect(),
+            }],
+        };
+        let keymaps = profile.keymaps();
+        assert_eq!(keymaps.len(), 2);
+        assert_eq!(
+            keymaps[0].layer_key(0x39),
+            Some(LayerKey {
+                layer: 1,
+                mode: LayerMode::Momentary
+            })
+        );
+        assert_eq!(keymaps[0].layer_key(0x3A), None);
+        assert!(keymaps[1].maps(0x23));
+        assert!(!keymaps[1].maps(0x24));
+        assert_eq!(keymaps[1].remaps.get(0x23), Some(0xE04B));
+    }
+
+    #[test]
+    fn test_actions() {
+        let profile = Profile {
+            name: "actions".to_string(),
+            keyboard: "US".to_string(),
+            scripts: vec![],
+            keys: [
+                ("0x3B".to_string(), "Ctrl + Shift+Z".into()),
+                ("0x3C".to_string(), "Ctrl+NoSuchKey".into()),
+                (
+                    "0x3D".to_string(),
+                    KeyMapping::Text(crate::TextMapping {
+                        text: "→".to_string(),
+                    }),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            ignored_for_matching: None,
+            sequence_timeout_ms: None,
+            layers: vec![],
+        };
+        let keymap = &profile.keymaps()[0];
+        assert_eq!(
+            keymap.actions,
+            [
+                (0x3B, KeyAction::Combo(vec![0x1D, 0x2A, 0x2C])),
+                (0x3D, KeyAction::Text("→".to_string())),
+            ]
+        );
+        assert_eq!(keymap.action_index(0x3D), Some(1));
+        assert!(!keymap.maps(0x3C));
+    }
+
+    #[test]
+    fn test_index_round_trip() {
+        for scancode in [0x00, 0x1E, 0xFF, 0xE01D, 0xE0FF] {
+            let index = scancode_index(scancode).unwrap();
+            assert!(index < SCANCODE_SLOTS);
+            assert_eq!(index_scancode(index), scancode);
+        }
+        assert_eq!(scancode_index(0xE11D), None);
+    }
+}